@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{report_text, Config, Error};
+
+/// 1つのストリームとして展開できる圧縮形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+/// 展開してから検索する入力の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// 1つのファイルを圧縮したもの
+    Compressed(Compression),
+    /// tarアーカイブ。全体が圧縮されていることもある
+    Tar(Option<Compression>),
+    Zip,
+}
+
+impl Format {
+    /// 拡張子から形式を判定する。展開が不要なファイルなら`None`を返す
+    pub(crate) fn detect(path: &Path) -> Option<Format> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let compression = [(".gz", Compression::Gzip), (".zst", Compression::Zstd), (".bz2", Compression::Bzip2)]
+            .iter()
+            .find(|(suffix, _)| name.ends_with(suffix));
+        if let Some(&(suffix, compression)) = compression {
+            let stem = &name[..name.len() - suffix.len()];
+            return Some(if stem.ends_with(".tar") {
+                Format::Tar(Some(compression))
+            } else {
+                Format::Compressed(compression)
+            });
+        }
+        match name.rsplit('.').next() {
+            Some("tar") => Some(Format::Tar(None)),
+            Some("tgz") => Some(Format::Tar(Some(Compression::Gzip))),
+            Some("tbz2") => Some(Format::Tar(Some(Compression::Bzip2))),
+            Some("tzst") => Some(Format::Tar(Some(Compression::Zstd))),
+            Some("zip") => Some(Format::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// 展開しながら読むストリームを作る
+/// ローテートされたログは連結されていることがあるので、gzipとbzip2は複数のメンバーを続けて読む
+fn decompress<'a, R: Read + 'a>(compression: Compression, input: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(input)?),
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(input)),
+    })
+}
+
+/// アーカイブのメンバーは`archive.tar:member/path`という名前で出力する
+fn member_path(archive: &Path, member: &str) -> PathBuf {
+    PathBuf::from(format!("{}:{}", archive.display(), member))
+}
+
+/// 圧縮ファイルやアーカイブを展開しながら検索する。戻り値は選択された行数
+/// 圧縮ファイルは元のパスのまま、アーカイブのメンバーは常にメンバー名付きで出力する
+/// アーカイブのメンバーはディレクトリを辿ったときと同じくバイナリなら読み飛ばす
+pub(crate) fn search<W: Write>(
+    out: &mut W,
+    path: &Path,
+    format: Format,
+    show_path: bool,
+    skip_binary: bool,
+    config: &Config,
+) -> Result<usize, Error> {
+    let io_error = |e| Error::from_io(path, e);
+    let file = File::open(path).map_err(io_error)?;
+    let mut selected = 0;
+    match format {
+        Format::Compressed(compression) => {
+            let reader = BufReader::with_capacity(64 * 1024, decompress(compression, file).map_err(io_error)?);
+            selected += report_text(out, reader, path, show_path, skip_binary, config).map_err(io_error)?;
+        }
+        Format::Tar(compression) => {
+            let input: Box<dyn Read> = match compression {
+                Some(compression) => decompress(compression, file).map_err(io_error)?,
+                None => Box::new(file),
+            };
+            let mut archive = tar::Archive::new(input);
+            for entry in archive.entries().map_err(io_error)? {
+                let entry = entry.map_err(io_error)?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let member = member_path(path, &entry.path().map_err(io_error)?.to_string_lossy());
+                let reader = BufReader::with_capacity(64 * 1024, entry);
+                selected += report_text(out, reader, &member, true, true, config).map_err(io_error)?;
+            }
+        }
+        Format::Zip => {
+            // zipは末尾の目次を読むためにシークが必要なので、ファイルを直接渡す
+            let mut archive = zip::ZipArchive::new(file).map_err(|e| io_error(e.into()))?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i).map_err(|e| io_error(e.into()))?;
+                if !entry.is_file() {
+                    continue;
+                }
+                let member = member_path(path, &entry.name().map_err(|e| io_error(e.into()))?);
+                let reader = BufReader::with_capacity(64 * 1024, entry);
+                selected += report_text(out, reader, &member, true, true, config).map_err(io_error)?;
+            }
+        }
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn config(query: &str) -> Config {
+        Config { patterns: vec![query.to_string()], case_sensitive: true, line_number: true, ..Config::default() }
+    }
+
+    fn search_to_string(path: &Path, config: &Config) -> String {
+        let mut out = Vec::new();
+        search(&mut out, path, Format::detect(path).unwrap(), false, false, config).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn detect_by_extension() {
+        assert_eq!(Some(Format::Compressed(Compression::Gzip)), Format::detect(Path::new("app.log.1.gz")));
+        assert_eq!(Some(Format::Compressed(Compression::Zstd)), Format::detect(Path::new("app.log.ZST")));
+        assert_eq!(Some(Format::Tar(Some(Compression::Bzip2))), Format::detect(Path::new("logs.tar.bz2")));
+        assert_eq!(Some(Format::Tar(Some(Compression::Gzip))), Format::detect(Path::new("logs.tgz")));
+        assert_eq!(Some(Format::Tar(None)), Format::detect(Path::new("dir/logs.tar")));
+        assert_eq!(Some(Format::Zip), Format::detect(Path::new("logs.zip")));
+        assert_eq!(None, Format::detect(Path::new("poem.txt")));
+        assert_eq!(None, Format::detect(Path::new("gz")));
+    }
+
+    #[test]
+    fn compressed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // 2つのgzipメンバーを連結したもの
+        let path = dir.path().join("app.log.gz");
+        let mut contents = Vec::new();
+        for part in &["GET /a 200\n", "GET /b 500\n"] {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            contents.extend(encoder.finish().unwrap());
+        }
+        fs::write(&path, contents).unwrap();
+        assert_eq!("2:GET /b 500\n", search_to_string(&path, &config("500")));
+
+        let path = dir.path().join("app.log.zst");
+        fs::write(&path, zstd::encode_all(&b"GET /a 200\nGET /b 500\n"[..], 0).unwrap()).unwrap();
+        assert_eq!("1:GET /a 200\n", search_to_string(&path, &config("200")));
+
+        let path = dir.path().join("app.log.bz2");
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(b"GET /a 200\nGET /b 500\n").unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        assert_eq!("2:GET /b 500\n", search_to_string(&path, &config("/b")));
+    }
+
+    #[test]
+    fn archive_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, contents) in &[("a/app.log", &b"ok\nerror: disk\n"[..]), ("b.bin", b"error\0"), ("c.log", b"error: net\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        let p = path.display();
+        assert_eq!(
+            format!("{}:a/app.log:2:error: disk\n{}:c.log:1:error: net\n", p, p),
+            search_to_string(&path, &config("error"))
+        );
+
+        let path = dir.path().join("logs.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer.add_directory("dir/", options).unwrap();
+        writer.start_file("dir/app.log", options).unwrap();
+        writer.write_all(b"ok\nerror: disk\n").unwrap();
+        writer.finish().unwrap();
+        let mut config = config("error");
+        config.count = true;
+        assert_eq!(format!("{}:dir/app.log:1\n", path.display()), search_to_string(&path, &config));
+    }
+}
@@ -0,0 +1,454 @@
+use std::fs;
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::Error;
+
+pub const USAGE: &str = "\
+Usage: minigrep [OPTIONS] QUERY FILE...
+       minigrep [OPTIONS] -e QUERY... FILE...
+       minigrep [OPTIONS] -f PATTERNS_FILE FILE...
+       minigrep index DIR...
+
+Search for QUERY in each FILE. A directory is searched recursively,
+and `-` reads standard input. Files ending in .gz, .zst or .bz2 are
+decompressed, and members of .tar (optionally compressed) and .zip
+archives are searched and reported as ARCHIVE:MEMBER.
+
+`minigrep index DIR` builds or refreshes a trigram index of DIR. Later
+literal searches of DIR use it to skip files that cannot match.
+
+Options:
+  -e, --regexp QUERY           search for the regular expression QUERY; may be
+                               given more than once
+  -f, --file FILE              search for each line of FILE
+  -E, --regex                  interpret queries as regular expressions
+  -F, --fixed-strings          interpret queries as literal strings, even with -e
+  -i, --ignore-case            ignore case distinctions
+  -w, --word-regexp            match only whole words
+      --fuzzy K                match lines containing QUERY within edit distance K,
+                               best matches first within each file
+  -v, --invert-match           select non-matching lines
+  -c, --count                  print only a count of matching lines per file
+  -l, --files-with-matches     print only names of files with matches
+  -n, --line-number            print line number with output lines
+  -A, --after-context NUM      print NUM lines of trailing context
+  -B, --before-context NUM     print NUM lines of leading context
+  -C, --context NUM            print NUM lines of output context
+      --replace TEXT           print lines with each match replaced by TEXT
+                               ($1 or ${name} refer to capture groups with -e or -E)
+      --in-place               rewrite files with the replacements applied
+      --dry-run                show the replacements as a diff without writing
+      --color[=WHEN]           highlight matches; WHEN is auto, always or never
+                               (default: auto, bare --color means always)
+      --json                   print one JSON object per match
+  -j, --jobs NUM               search NUM files in parallel (default: number of CPUs)
+  -h, --help                   display this help and exit
+  -V, --version                display version information and exit";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `--color`の指定。`Auto`は標準出力が端末のときだけ色を付ける
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// 実行するコマンド。最初の引数が`index`なら索引の作成になる
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    #[default]
+    Search,
+    Index,
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub command: Command,
+    /// 検索するパターン。どれか1つにマッチした行が選ばれる
+    pub patterns: Vec<String>,
+    pub filenames: Vec<String>,
+    pub case_sensitive: bool,
+    /// `--regex`のとき、すべてのパターンを1つにまとめてコンパイルした正規表現
+    /// `-w`なら単語の境界の条件もここに含める
+    pub pattern: Option<Regex>,
+    pub word_regexp: bool,
+    /// `--fuzzy`で許す編集距離の上限
+    pub fuzzy: Option<usize>,
+    pub invert_match: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub line_number: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    /// 並列に検索するファイル数。0なら利用可能なCPU数
+    pub jobs: usize,
+    pub replace: Option<String>,
+    pub in_place: bool,
+    pub dry_run: bool,
+    pub color: ColorChoice,
+    pub json: bool,
+    pub help: bool,
+    pub version: bool,
+}
+impl Config {
+    /// 先頭要素をプログラム名とみなして引数列を解釈する
+    /// `std::env::Args`に限らず任意の文字列のイテレータから構築できる
+    pub fn new(args: impl Iterator<Item = String>) -> Result<Config, Error> {
+        let mut args = args.skip(1).peekable();
+        let mut config = Config { case_sensitive: true, ..Config::default() };
+        if args.next_if(|arg| arg == "index").is_some() {
+            config.command = Command::Index;
+        }
+        let mut use_regex = false;
+        let mut fixed_strings = false;
+        // `-e`で渡したパターンは`-F`がなければ正規表現として扱う
+        let mut regexp_option = false;
+        // `-f`で読んだファイルが空でも、最初の引数はパターンではなくファイルとして扱う
+        let mut patterns_file = false;
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+            } else if let Some(long) = arg.strip_prefix("--") {
+                // `--name=value`と`--name value`の両方を受け付ける
+                let (name, value) = match long.find('=') {
+                    Some(i) => (&long[..i], Some(long[i + 1..].to_string())),
+                    None => (long, None),
+                };
+                match name {
+                    "regexp" => match value.or_else(|| args.next()) {
+                        Some(pattern) => {
+                            config.patterns.push(pattern);
+                            regexp_option = true;
+                        }
+                        None => return Err(Error::MissingArgument("query")),
+                    },
+                    "file" => match value.or_else(|| args.next()) {
+                        Some(file) => {
+                            config.patterns.extend(read_patterns(&file)?);
+                            patterns_file = true;
+                        }
+                        None => return Err(Error::MissingArgument("patterns file")),
+                    },
+                    "regex" => use_regex = true,
+                    "fixed-strings" => fixed_strings = true,
+                    "ignore-case" => config.case_sensitive = false,
+                    "word-regexp" => config.word_regexp = true,
+                    "fuzzy" => {
+                        let value = value.or_else(|| args.next());
+                        match value.as_deref().map(str::parse) {
+                            Some(Ok(k)) => config.fuzzy = Some(k),
+                            _ => return Err(Error::InvalidArgument(format!(
+                                "Edit distance must be a non-negative integer: {}", value.unwrap_or_default()
+                            ))),
+                        }
+                    }
+                    "invert-match" => config.invert_match = true,
+                    "count" => config.count = true,
+                    "files-with-matches" => config.files_with_matches = true,
+                    "line-number" => config.line_number = true,
+                    "after-context" => config.after_context = parse_context(value.or_else(|| args.next()))?,
+                    "before-context" => config.before_context = parse_context(value.or_else(|| args.next()))?,
+                    "context" => {
+                        config.after_context = parse_context(value.or_else(|| args.next()))?;
+                        config.before_context = config.after_context;
+                    }
+                    "jobs" => config.jobs = parse_jobs(value.or_else(|| args.next()))?,
+                    "color" | "colour" => {
+                        config.color = match value.as_deref() {
+                            None | Some("always") => ColorChoice::Always,
+                            Some("auto") => ColorChoice::Auto,
+                            Some("never") => ColorChoice::Never,
+                            Some(other) => return Err(Error::InvalidArgument(format!("Color must be one of auto, always or never: {}", other))),
+                        }
+                    }
+                    "json" => config.json = true,
+                    "replace" => match value.or_else(|| args.next()) {
+                        Some(text) => config.replace = Some(text),
+                        None => return Err(Error::MissingArgument("replacement text")),
+                    },
+                    "in-place" => config.in_place = true,
+                    "dry-run" => config.dry_run = true,
+                    "help" => config.help = true,
+                    "version" => config.version = true,
+                    _ => return Err(Error::InvalidArgument(format!("Unknown option: --{}", name))),
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                // `-in`のようにまとめて指定された短いオプションを1文字ずつ処理する
+                for (i, flag) in arg.char_indices().skip(1) {
+                    // 値を取るオプションは`-A3`のように続けて書くか、次の引数として渡す
+                    let rest = &arg[i + flag.len_utf8()..];
+                    let mut value = || if rest.is_empty() { args.next() } else { Some(rest.to_string()) };
+                    match flag {
+                        'e' => {
+                            match value() {
+                                Some(pattern) => config.patterns.push(pattern),
+                                None => return Err(Error::MissingArgument("query")),
+                            }
+                            regexp_option = true;
+                            break;
+                        }
+                        'f' => {
+                            match value() {
+                                Some(file) => config.patterns.extend(read_patterns(&file)?),
+                                None => return Err(Error::MissingArgument("patterns file")),
+                            }
+                            patterns_file = true;
+                            break;
+                        }
+                        'E' => use_regex = true,
+                        'F' => fixed_strings = true,
+                        'i' => config.case_sensitive = false,
+                        'w' => config.word_regexp = true,
+                        'v' => config.invert_match = true,
+                        'c' => config.count = true,
+                        'l' => config.files_with_matches = true,
+                        'n' => config.line_number = true,
+                        'h' => config.help = true,
+                        'V' => config.version = true,
+                        'A' | 'B' | 'C' => {
+                            let n = parse_context(value())?;
+                            if flag != 'B' { config.after_context = n; }
+                            if flag != 'A' { config.before_context = n; }
+                            break;
+                        }
+                        'j' => {
+                            config.jobs = parse_jobs(value())?;
+                            break;
+                        }
+                        _ => return Err(Error::InvalidArgument(format!("Unknown option: -{}", flag))),
+                    }
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+        if config.help || config.version {
+            return Ok(config);
+        }
+
+        if config.command == Command::Index {
+            if positional.is_empty() {
+                return Err(Error::MissingArgument("directory"));
+            }
+            config.filenames = positional;
+            return Ok(config);
+        }
+
+        let mut positional = positional.into_iter();
+        // `-e`や`-f`がなければ最初の引数をパターンとする
+        if !regexp_option && !patterns_file {
+            match positional.next() {
+                Some(arg) => config.patterns.push(arg),
+                None => return Err(Error::MissingArgument("querystring")),
+            }
+        }
+        config.filenames = positional.collect();
+        if config.filenames.is_empty() {
+            return Err(Error::MissingArgument("filename"));
+        }
+        if (config.in_place || config.dry_run) && config.replace.is_none() {
+            return Err(Error::InvalidArgument("--in-place and --dry-run require --replace".to_string()));
+        }
+        if config.replace.is_some() && config.invert_match {
+            return Err(Error::InvalidArgument("--replace cannot be combined with --invert-match".to_string()));
+        }
+        if use_regex && fixed_strings {
+            return Err(Error::InvalidArgument("--regex cannot be combined with --fixed-strings".to_string()));
+        }
+        if config.fuzzy.is_some() {
+            let conflicting = [
+                (use_regex, "--regex"),
+                (config.invert_match, "--invert-match"),
+                (config.word_regexp, "--word-regexp"),
+                (config.replace.is_some(), "--replace"),
+                (config.before_context > 0 || config.after_context > 0, "context options"),
+            ];
+            if let Some((_, option)) = conflicting.iter().find(|(set, _)| *set) {
+                return Err(Error::InvalidArgument(format!("--fuzzy cannot be combined with {}", option)));
+            }
+        }
+        // `--fuzzy`のパターンは常に文字列として扱う
+        // パターンが1つもなければ何にもマッチしない。空の選択は空文字列に、つまりすべての行にマッチしてしまう
+        if !config.patterns.is_empty() && (use_regex || (regexp_option && !fixed_strings && config.fuzzy.is_none())) {
+            // 個別にコンパイルしてどのパターンが不正なのかを報告してから、1つの選択にまとめる
+            for pattern in &config.patterns {
+                build_regex(pattern, config.case_sensitive).map_err(Error::InvalidPattern)?;
+            }
+            let alternation: Vec<String> = config.patterns.iter().map(|p| format!("(?:{})", p)).collect();
+            let mut pattern = alternation.join("|");
+            if config.word_regexp {
+                pattern = whole_word(&pattern);
+            }
+            let pattern = build_regex(&pattern, config.case_sensitive);
+            config.pattern = Some(pattern.map_err(Error::InvalidPattern)?);
+        }
+        Ok(config)
+    }
+}
+
+/// 大文字小文字の区別はパターンのコンパイル時に指定する
+pub(crate) fn build_regex(pattern: &str, case_sensitive: bool) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(!case_sensitive).build()
+}
+
+/// 前後が単語を構成する文字でない位置にだけマッチするようにパターンを包む
+/// マッチを見つけてから境界を確かめるのと違い、同じ位置から始まる長い候補も正規表現エンジンが試してくれる
+pub(crate) fn whole_word(pattern: &str) -> String {
+    format!(r"\b{{start-half}}(?:{})\b{{end-half}}", pattern)
+}
+
+/// パターンファイルの1行を1つのパターンとして読み込む
+fn read_patterns(file: &str) -> Result<Vec<String>, Error> {
+    let contents = fs::read_to_string(file).map_err(|e| Error::from_io(Path::new(file), e))?;
+    Ok(contents.lines().map(String::from).collect())
+}
+
+fn parse_context(arg: Option<String>) -> Result<usize, Error> {
+    match arg.as_deref().map(str::parse) {
+        Some(Ok(n)) => Ok(n),
+        _ => Err(Error::InvalidArgument(format!("Context length must be a non-negative integer: {}", arg.unwrap_or_default()))),
+    }
+}
+
+fn parse_jobs(arg: Option<String>) -> Result<usize, Error> {
+    match arg.as_deref().map(str::parse) {
+        Some(Ok(n)) if n > 0 => Ok(n),
+        _ => Err(Error::InvalidArgument(format!("Number of jobs must be a positive integer: {}", arg.unwrap_or_default()))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, Error> {
+        Config::new(std::iter::once("minigrep").chain(args.iter().copied()).map(String::from))
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let config = parse(&["body", "poem.txt", "src"]).unwrap();
+        assert_eq!(vec!["body"], config.patterns);
+        assert_eq!(vec!["poem.txt", "src"], config.filenames);
+        assert!(config.case_sensitive);
+        assert!(config.pattern.is_none());
+    }
+
+    #[test]
+    fn flags() {
+        let config = parse(&["-inv", "--count", "-A2", "--before-context=3", "--", "-body", "poem.txt"]).unwrap();
+        assert!(!config.case_sensitive);
+        assert!(config.line_number && config.invert_match && config.count);
+        assert_eq!((3, 2), (config.before_context, config.after_context));
+        assert_eq!(vec!["-body"], config.patterns);
+
+        let config = parse(&["-lEC", "1", "^bo(dy|g)", "poem.txt"]).unwrap();
+        assert!(config.files_with_matches);
+        assert!(!config.word_regexp);
+        assert!(parse(&["-w", "body", "poem.txt"]).unwrap().word_regexp);
+        assert!(config.pattern.unwrap().is_match("body"));
+        assert_eq!((1, 1), (config.before_context, config.after_context));
+        assert_eq!(0, config.jobs);
+        assert_eq!(ColorChoice::Auto, config.color);
+
+        assert_eq!(4, parse(&["-j4", "body", "poem.txt"]).unwrap().jobs);
+        assert_eq!(2, parse(&["--jobs", "2", "body", "poem.txt"]).unwrap().jobs);
+    }
+
+    #[test]
+    fn multiple_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("patterns.txt");
+        fs::write(&file, "frog\nbog\n").unwrap();
+        let config = parse(&["-e", "body", "-etoo", "--file", file.to_str().unwrap(), "poem.txt", "src"]).unwrap();
+        assert_eq!(vec!["body", "too", "frog", "bog"], config.patterns);
+        assert_eq!(vec!["poem.txt", "src"], config.filenames);
+        // `-e`のパターンは正規表現になる。`-F`なら文字列のまま
+        assert!(config.pattern.is_some());
+        assert!(parse(&["-F", "-e", "a.c", "poem.txt"]).unwrap().pattern.is_none());
+        assert!(parse(&["-f", file.to_str().unwrap(), "poem.txt"]).unwrap().pattern.is_none());
+        assert!(parse(&["-e", "^bo(dy|g)", "poem.txt"]).unwrap().pattern.unwrap().is_match("bog"));
+        assert!(matches!(parse(&["-EF", "body", "poem.txt"]), Err(Error::InvalidArgument(_))));
+
+        // 空のパターンファイルはパターンなしで、何にもマッチしない
+        let empty = dir.path().join("empty.txt");
+        fs::write(&empty, "").unwrap();
+        let config = parse(&["-E", "-f", empty.to_str().unwrap(), "poem.txt"]).unwrap();
+        assert!(config.patterns.is_empty() && config.pattern.is_none());
+        assert_eq!(vec!["poem.txt"], config.filenames);
+
+        let config = parse(&["-E", "--regexp=^bo", "-e", "g!$", "poem.txt"]).unwrap();
+        let pattern = config.pattern.unwrap();
+        assert!(pattern.is_match("bog") && pattern.is_match("admiring bog!") && !pattern.is_match("a bog"));
+    }
+
+    #[test]
+    fn fuzzy_option() {
+        assert_eq!(Some(2), parse(&["--fuzzy", "2", "body", "poem.txt"]).unwrap().fuzzy);
+        assert_eq!(None, parse(&["body", "poem.txt"]).unwrap().fuzzy);
+        assert!(matches!(parse(&["--fuzzy=x", "body", "poem.txt"]), Err(Error::InvalidArgument(_))));
+        assert_eq!(
+            "--fuzzy cannot be combined with --regex",
+            parse(&["--fuzzy=1", "-E", "body", "poem.txt"]).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn output_options() {
+        let config = parse(&["--color", "--json", "body", "poem.txt"]).unwrap();
+        assert_eq!(ColorChoice::Always, config.color);
+        assert!(config.json);
+        assert_eq!(ColorChoice::Never, parse(&["--color=never", "body", "poem.txt"]).unwrap().color);
+        assert!(parse(&["--color=sometimes", "body", "poem.txt"]).is_err());
+    }
+
+    #[test]
+    fn replace_options() {
+        let config = parse(&["--replace=toad", "--dry-run", "frog", "poem.txt"]).unwrap();
+        assert_eq!(Some("toad".to_string()), config.replace);
+        assert!(config.dry_run && !config.in_place);
+        assert!(parse(&["--replace", "", "--in-place", "frog", "poem.txt"]).unwrap().in_place);
+        assert!(matches!(parse(&["--in-place", "frog", "poem.txt"]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(parse(&["-v", "--replace", "x", "frog", "poem.txt"]), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn index_command() {
+        let config = parse(&["index", "src"]).unwrap();
+        assert_eq!(Command::Index, config.command);
+        assert_eq!(vec!["src"], config.filenames);
+        assert!(matches!(parse(&["index"]), Err(Error::MissingArgument("directory"))));
+
+        let config = parse(&["--", "index", "poem.txt"]).unwrap();
+        assert_eq!(Command::Search, config.command);
+        assert_eq!(vec!["index"], config.patterns);
+    }
+
+    #[test]
+    fn help_and_version_need_no_arguments() {
+        assert!(parse(&["--help"]).unwrap().help);
+        assert!(parse(&["-V"]).unwrap().version);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(parse(&[]), Err(Error::MissingArgument("querystring"))));
+        assert!(matches!(parse(&["body"]), Err(Error::MissingArgument("filename"))));
+        assert_eq!("Unknown option: -x", parse(&["-x", "body", "poem.txt"]).unwrap_err().to_string());
+        assert_eq!("Unknown option: --frobnicate", parse(&["--frobnicate", "body", "poem.txt"]).unwrap_err().to_string());
+        assert_eq!(
+            "Context length must be a non-negative integer: x",
+            parse(&["-A", "x", "body", "poem.txt"]).unwrap_err().to_string()
+        );
+        assert!(matches!(parse(&["-j0", "body", "poem.txt"]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(parse(&["-E", "-e", "body", "-e", "(", "poem.txt"]), Err(Error::InvalidPattern(_))));
+        assert!(matches!(parse(&["-e"]), Err(Error::MissingArgument("query"))));
+        assert!(matches!(parse(&["-f", "no-such-file", "poem.txt"]), Err(Error::FileNotFound(_))));
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// minigrepの失敗を種類ごとに区別するためのエラー
+#[derive(Debug)]
+pub enum Error {
+    /// 必須の引数が与えられなかった。値は引数の名前
+    MissingArgument(&'static str),
+    /// 知らないオプションやオプションの不正な値
+    InvalidArgument(String),
+    /// 正規表現としてコンパイルできないパターン
+    InvalidPattern(regex::Error),
+    FileNotFound(PathBuf),
+    PermissionDenied(PathBuf),
+    /// 上記以外の入出力エラー。パスは入力ファイルに関するエラーのときだけ持つ
+    Io(Option<PathBuf>, io::Error),
+    /// ディレクトリを辿る途中で起きたエラー
+    Walk(ignore::Error),
+}
+
+impl Error {
+    /// ファイルを開いたり読んだりしたときのエラーを、よくある種類については専用の値に振り分ける
+    pub fn from_io(path: &Path, err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::FileNotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+            _ => Error::Io(Some(path.to_path_buf()), err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingArgument(name) => write!(f, "Didn't get required param: {}", name),
+            Error::InvalidArgument(message) => write!(f, "{}", message),
+            Error::InvalidPattern(err) => write!(f, "Invalid regular expression: {}", err),
+            Error::FileNotFound(path) => write!(f, "{}: No such file or directory", path.display()),
+            Error::PermissionDenied(path) => write!(f, "{}: Permission denied", path.display()),
+            Error::Io(Some(path), err) => write!(f, "{}: {}", path.display(), err),
+            Error::Io(None, err) => write!(f, "{}", err),
+            Error::Walk(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidPattern(err) => Some(err),
+            Error::Io(_, err) => Some(err),
+            Error::Walk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(None, err)
+    }
+}
+
+impl From<ignore::Error> for Error {
+    fn from(err: ignore::Error) -> Error {
+        Error::Walk(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn io_errors_are_classified() {
+        let path = Path::new("poem.txt");
+        let not_found = Error::from_io(path, io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(not_found, Error::FileNotFound(_)));
+        assert_eq!("poem.txt: No such file or directory", not_found.to_string());
+
+        let denied = Error::from_io(path, io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(denied, Error::PermissionDenied(_)));
+
+        let other = Error::from_io(path, io::Error::from(io::ErrorKind::InvalidData));
+        assert!(matches!(other, Error::Io(Some(_), _)));
+        assert!(other.source().is_some());
+    }
+}
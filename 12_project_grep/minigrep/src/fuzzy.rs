@@ -0,0 +1,57 @@
+use std::ops::Range;
+
+/// `line`の部分文字列のうち`query`との編集距離(挿入・削除・置換の回数)が最小のものを探す
+/// Sellersのアルゴリズムで、部分文字列の開始位置は自由に選べるように動的計画法の最初の行を0にする
+/// 戻り値は最小の距離と、その距離を与える最も左の部分文字列のバイト範囲
+/// 行ごとに文字の配列を作るので、通常の検索よりは遅い
+pub fn fuzzy_find(query: &str, line: &str, case_sensitive: bool) -> (usize, Range<usize>) {
+    let fold = |c: char| if case_sensitive { c } else { c.to_lowercase().next().unwrap_or(c) };
+    let query: Vec<char> = query.chars().map(fold).collect();
+    let text: Vec<(usize, char)> = line.char_indices().map(|(i, c)| (i, fold(c))).collect();
+    let byte_at = |j: usize| text.get(j).map_or(line.len(), |(i, _)| *i);
+
+    // column[i] = (query[..i]と、text[..j]の末尾で終わる部分文字列との最小距離, その部分文字列の開始位置)
+    let mut column: Vec<(usize, usize)> = (0..=query.len()).map(|i| (i, 0)).collect();
+    let mut best = (query.len(), 0, 0);
+    for (j, &(_, c)) in text.iter().enumerate() {
+        let mut diagonal = column[0];
+        column[0] = (0, j + 1);
+        for i in 1..=query.len() {
+            let substitute = (diagonal.0 + usize::from(query[i - 1] != c), diagonal.1);
+            let delete = (column[i].0 + 1, column[i].1);
+            let insert = (column[i - 1].0 + 1, column[i - 1].1);
+            diagonal = column[i];
+            column[i] = *[substitute, delete, insert].iter().min_by_key(|(d, _)| *d).unwrap();
+        }
+        let (distance, start) = column[query.len()];
+        if distance < best.0 {
+            best = (distance, start, j + 1);
+        }
+    }
+    let (distance, start, end) = best;
+    (distance, byte_at(start)..byte_at(end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exact_and_approximate() {
+        assert_eq!((0, 6..10), fuzzy_find("body", "I'm nobody!", true));
+        // 置換1回
+        assert_eq!((1, 6..10), fuzzy_find("bady", "I'm nobody!", true));
+        // 削除1回と挿入1回
+        assert_eq!((1, 2..8), fuzzy_find("connect", "a conect error", true));
+        assert_eq!((1, 2..9), fuzzy_find("conect", "a connect error", true));
+        assert_eq!((2, 0..0), fuzzy_find("ab", "", true));
+    }
+
+    #[test]
+    fn case_and_multibyte() {
+        assert_eq!(0, fuzzy_find("RUST", "trust", false).0);
+        assert_eq!(4, fuzzy_find("RUST", "trust", true).0);
+        // 語末のςは大文字Σを小文字にしたσと一致しないので、末尾の1文字を除いた範囲が距離1で見つかる
+        assert_eq!((1, 3..15), fuzzy_find("σίσυφος", "ο ΣΊΣΥΦΟΣ", false));
+    }
+}
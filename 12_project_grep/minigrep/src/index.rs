@@ -0,0 +1,355 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use tempfile::NamedTempFile;
+
+use crate::archive::Format;
+use crate::{is_binary, walk, Config, Error};
+
+/// 索引はディレクトリ直下のこの名前のファイルに保存する
+/// `walk`はこの名前のファイルを除外するので、検索の対象にも索引の対象にもならない
+pub const INDEX_FILE: &str = ".minigrep-index";
+
+const MAGIC: &[u8; 8] = b"MGIDX01\n";
+
+/// 索引を作ったときのファイルの状態。これが変わっていれば索引の内容は信用しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Stamp> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Stamp { size: metadata.len(), mtime_secs: mtime.as_secs(), mtime_nanos: mtime.subsec_nanos() })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    stamp: Stamp,
+    /// ファイルに現れるトライグラム(昇順)。バイナリファイルでは空
+    trigrams: Vec<u32>,
+}
+
+/// 索引を更新したときの件数
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    pub files: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// ファイルごとに含まれるトライグラム(連続する3バイト)を記録した索引
+/// クエリのトライグラムをすべて含むファイルだけが候補になるので、それ以外は開かずに済む
+/// ASCIIの大文字は小文字に揃えて記録するので、大文字小文字を区別しない検索にも使える
+#[derive(Debug, Default)]
+pub struct Index {
+    /// ディレクトリからの相対パスをキーにする
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Index {
+    /// 保存済みの索引を読み込む。索引がなければ`None`を返す
+    pub fn load(dir: &Path) -> Result<Option<Index>, Error> {
+        let path = dir.join(INDEX_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from_io(&path, e)),
+        };
+        Index::read_from(BufReader::new(file)).map(Some).map_err(|e| Error::from_io(&path, e))
+    }
+
+    /// ディレクトリの索引を作り直して保存する
+    /// 前回から大きさも更新日時も変わっていないファイルは読み直さずに以前の結果を使う
+    pub fn update(dir: &Path) -> Result<IndexStats, Error> {
+        let mut old = Index::load(dir)?.unwrap_or_default();
+        let mut index = Index::default();
+        let mut stats = IndexStats::default();
+        // 索引の作成は明示的に頼まれた操作なので、辿れなかった場所があれば保存せずに知らせる
+        let (files, mut errors) = walk(dir);
+        if !errors.is_empty() {
+            return Err(errors.swap_remove(0));
+        }
+        for path in files {
+            // 圧縮ファイルやアーカイブは索引に載せず、検索時には常に候補とする
+            if Format::detect(&path).is_some() {
+                continue;
+            }
+            let stamp = Stamp::of(&path).map_err(|e| Error::from_io(&path, e))?;
+            let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            let entry = match old.entries.remove(&relative) {
+                Some(entry) if entry.stamp == stamp => entry,
+                _ => {
+                    stats.updated += 1;
+                    let file = File::open(&path).map_err(|e| Error::from_io(&path, e))?;
+                    let trigrams = trigrams_of(file).map_err(|e| Error::from_io(&path, e))?;
+                    Entry { stamp, trigrams }
+                }
+            };
+            index.entries.insert(relative, entry);
+        }
+        stats.files = index.entries.len();
+        stats.removed = old.entries.len();
+        index.save(dir)?;
+        Ok(stats)
+    }
+
+    /// 一時ファイルに書いてからrenameするので、検索中の別プロセスが壊れた索引を読むことはない
+    fn save(&self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join(INDEX_FILE);
+        let io_error = |e| Error::from_io(&path, e);
+        let mut temp = BufWriter::new(NamedTempFile::new_in(dir).map_err(io_error)?);
+        self.write_to(&mut temp).map_err(io_error)?;
+        let temp = temp.into_inner().map_err(|e| io_error(e.into_error()))?;
+        temp.persist(&path).map_err(|e| io_error(e.error))?;
+        Ok(())
+    }
+
+    /// `path`を検索する必要があるかどうか。どれか1つのパターンのトライグラムをすべて含めば候補になる
+    /// 索引にないファイルや索引作成後に変更されたファイルは、中身が分からないので常に候補とする
+    pub fn is_candidate(&self, dir: &Path, path: &Path, queries: &[Vec<u32>]) -> bool {
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        match self.entries.get(relative) {
+            Some(entry) if Stamp::of(path).is_ok_and(|stamp| stamp == entry.stamp) => queries
+                .iter()
+                .any(|query| query.iter().all(|t| entry.trigrams.binary_search(t).is_ok())),
+            _ => true,
+        }
+    }
+
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        let mut paths: Vec<_> = self.entries.keys().collect();
+        paths.sort();
+        for path in paths {
+            let entry = &self.entries[path];
+            let path = path.to_string_lossy();
+            out.write_all(&(path.len() as u32).to_le_bytes())?;
+            out.write_all(path.as_bytes())?;
+            out.write_all(&entry.stamp.size.to_le_bytes())?;
+            out.write_all(&entry.stamp.mtime_secs.to_le_bytes())?;
+            out.write_all(&entry.stamp.mtime_nanos.to_le_bytes())?;
+            out.write_all(&(entry.trigrams.len() as u32).to_le_bytes())?;
+            for trigram in &entry.trigrams {
+                out.write_all(&trigram.to_le_bytes()[..3])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 長さの欄は壊れていても信用せず、実際に読めた分だけバッファを伸ばす
+    /// 書かれた長さの分を読めなければ`InvalidData`を返す
+    fn read_from<R: Read>(mut input: R) -> io::Result<Index> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a minigrep index"));
+        }
+        let mut index = Index::default();
+        for _ in 0..read_u64(&mut input)? {
+            let len = read_u32(&mut input)?;
+            let path = read_bytes(&mut input, u64::from(len))?;
+            let path = String::from_utf8(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let stamp = Stamp {
+                size: read_u64(&mut input)?,
+                mtime_secs: read_u64(&mut input)?,
+                mtime_nanos: read_u32(&mut input)?,
+            };
+            let count = read_u32(&mut input)?;
+            let trigrams = read_bytes(&mut input, u64::from(count) * 3)?
+                .chunks_exact(3)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+                .collect();
+            index.entries.insert(PathBuf::from(path), Entry { stamp, trigrams });
+        }
+        Ok(index)
+    }
+}
+
+/// `len`バイトを読む。先に`len`バイトを確保しないので、壊れた長さの欄で巨大な確保をすることはない
+fn read_bytes<R: Read>(input: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(truncated());
+    }
+    Ok(bytes)
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes).map_err(truncated_if_eof)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes).map_err(truncated_if_eof)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated minigrep index")
+}
+
+fn truncated_if_eof(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        truncated()
+    } else {
+        e
+    }
+}
+
+fn trigram(a: u8, b: u8, c: u8) -> u32 {
+    (a.to_ascii_lowercase() as u32) << 16 | (b.to_ascii_lowercase() as u32) << 8 | c.to_ascii_lowercase() as u32
+}
+
+/// 入力を少しずつ読みながらトライグラムを集める。バイナリなら空を返す
+fn trigrams_of<R: Read>(input: R) -> io::Result<Vec<u32>> {
+    let mut reader = BufReader::with_capacity(64 * 1024, input);
+    if is_binary(reader.fill_buf()?) {
+        return Ok(Vec::new());
+    }
+    let mut set = HashSet::new();
+    // バッファの境界をまたぐトライグラムのために直前の2バイトを覚えておく
+    let (mut first, mut second) = (None, None);
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            break;
+        }
+        for &c in chunk {
+            if let (Some(a), Some(b)) = (first, second) {
+                set.insert(trigram(a, b, c));
+            }
+            first = second;
+            second = Some(c);
+        }
+        let len = chunk.len();
+        reader.consume(len);
+    }
+    let mut trigrams: Vec<u32> = set.into_iter().collect();
+    trigrams.sort_unstable();
+    Ok(trigrams)
+}
+
+/// 大文字小文字を区別しない検索で、ASCII以外の文字にもマッチし得るASCII文字
+/// Unicodeの単純ケースフォールディングでは`k`はKelvin記号(U+212A)と、`s`は長いs(U+017F)と同じ文字になる
+/// 索引はASCIIの大文字小文字しか揃えていないので、これらを含むトライグラムは絞り込みに使えない
+const NON_ASCII_FOLDS: &[u8] = b"ks";
+
+/// 索引で候補を絞り込めるクエリなら、パターンごとのトライグラムを返す
+/// 正規表現・`-v`・`--fuzzy`・非ASCIIの大文字小文字を区別しない検索・3バイト未満のパターンがあると使えない
+pub fn query_trigrams(config: &Config) -> Option<Vec<Vec<u32>>> {
+    if config.pattern.is_some() || config.invert_match || config.fuzzy.is_some() || config.patterns.is_empty() {
+        return None;
+    }
+    config.patterns.iter().map(|pattern| {
+        let query = pattern.as_bytes();
+        if query.len() < 3 || (!config.case_sensitive && !query.is_ascii()) {
+            return None;
+        }
+        let mut trigrams: Vec<u32> = query
+            .windows(3)
+            .filter(|w| config.case_sensitive || !w.iter().any(|b| NON_ASCII_FOLDS.contains(&b.to_ascii_lowercase())))
+            .map(|w| trigram(w[0], w[1], w[2]))
+            .collect();
+        trigrams.sort_unstable();
+        trigrams.dedup();
+        Some(trigrams)
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trigrams_are_case_folded() {
+        let trigrams = trigrams_of(&b"aBcd"[..]).unwrap();
+        assert_eq!(vec![trigram(b'a', b'b', b'c'), trigram(b'b', b'c', b'd')], trigrams);
+        assert!(trigrams_of(&b"ab\0cd"[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn update_is_incremental() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "How public, like a frog").unwrap();
+        fs::write(dir.path().join("b.txt"), "To an admiring bog!").unwrap();
+        let stats = Index::update(dir.path()).unwrap();
+        assert_eq!(IndexStats { files: 2, updated: 2, removed: 0 }, stats);
+
+        fs::remove_file(dir.path().join("b.txt")).unwrap();
+        fs::write(dir.path().join("c.txt"), "frogs").unwrap();
+        let stats = Index::update(dir.path()).unwrap();
+        assert_eq!(IndexStats { files: 2, updated: 1, removed: 1 }, stats);
+
+        let index = Index::load(dir.path()).unwrap().unwrap();
+        let config = Config { patterns: vec!["FROG".to_string()], ..Config::default() };
+        let query = query_trigrams(&config).unwrap();
+        assert!(index.is_candidate(dir.path(), &dir.path().join("a.txt"), &query));
+        let config = Config { patterns: vec!["public".to_string()], case_sensitive: true, ..Config::default() };
+        let query = query_trigrams(&config).unwrap();
+        assert!(!index.is_candidate(dir.path(), &dir.path().join("c.txt"), &query));
+        // 索引作成後に書き換えられたファイルは常に候補になる
+        fs::write(dir.path().join("c.txt"), "nothing public here").unwrap();
+        assert!(index.is_candidate(dir.path(), &dir.path().join("c.txt"), &query));
+    }
+
+    #[test]
+    fn corrupt_index() {
+        let mut index = Index::default();
+        let stamp = Stamp { size: 5, mtime_secs: 1, mtime_nanos: 2 };
+        index.entries.insert(PathBuf::from("a.txt"), Entry { stamp, trigrams: trigrams_of(&b"frogs"[..]).unwrap() });
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        let read = Index::read_from(&bytes[..]).unwrap();
+        assert_eq!(index.entries[Path::new("a.txt")].trigrams, read.entries[Path::new("a.txt")].trigrams);
+
+        let invalid = |bytes: &[u8]| Index::read_from(bytes).unwrap_err().kind() == io::ErrorKind::InvalidData;
+        for len in [10, 20, bytes.len() - 1] {
+            assert!(invalid(&bytes[..len]), "truncated at {}", len);
+        }
+        // 長さの欄が壊れていても、その分のメモリを確保しようとせずに失敗する
+        let count = bytes.len() - 3 * 3 - 4;
+        let mut corrupt = bytes.clone();
+        corrupt[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(invalid(&corrupt));
+        let mut corrupt = bytes.clone();
+        corrupt[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(invalid(&corrupt));
+    }
+
+    #[test]
+    fn case_folding_beyond_ascii() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "0 \u{212A}elvin is absolute zero").unwrap();
+        Index::update(dir.path()).unwrap();
+        let index = Index::load(dir.path()).unwrap().unwrap();
+        // `-i kelvin`は通常の検索で`Kelvin`にマッチするので、索引でも候補から外さない
+        let config = Config { patterns: vec!["kelvin".to_string()], ..Config::default() };
+        let query = query_trigrams(&config).unwrap();
+        assert_eq!(3, query[0].len());
+        assert!(index.is_candidate(dir.path(), &dir.path().join("a.txt"), &query));
+        let config = Config { patterns: vec!["kelvin".to_string()], case_sensitive: true, ..Config::default() };
+        assert!(!index.is_candidate(dir.path(), &dir.path().join("a.txt"), &query_trigrams(&config).unwrap()));
+    }
+
+    #[test]
+    fn unusable_queries() {
+        let patterns = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        assert!(query_trigrams(&Config { patterns: patterns(&["frog", "ab"]), ..Config::default() }).is_none());
+        assert!(query_trigrams(&Config { patterns: patterns(&["straße"]), ..Config::default() }).is_none());
+        let config = Config { patterns: patterns(&["straße", "frog"]), case_sensitive: true, ..Config::default() };
+        assert_eq!(2, query_trigrams(&config).unwrap().len());
+    }
+}
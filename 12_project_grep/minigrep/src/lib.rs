@@ -1,64 +1,391 @@
+use std::borrow::Cow;
 use std::fs::File;
-use std::error::Error;
-use std::io::Read;
-use std::env;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use ignore::WalkBuilder;
 
-pub struct Config { 
-    pub query: String, 
-    pub filename: String,
-    pub case_sensitive: bool
+mod archive;
+mod config;
+mod error;
+mod fuzzy;
+mod index;
+mod matcher;
+mod parallel;
+mod printer;
+mod replace;
+mod searcher;
+
+pub use config::{ColorChoice, Command, Config, USAGE, VERSION};
+pub use error::Error;
+pub use index::{Index, IndexStats, INDEX_FILE};
+pub use matcher::{CaseInsensitive, PatternMatcher};
+pub use searcher::{Matcher, Matches, Searcher};
+use fuzzy::fuzzy_find;
+use printer::ContextPrinter;
+use searcher::LineReader;
+
+/// 検索でヒットした1行分の情報
+/// 文字列全体を検索したときは行を借用し、`Searcher`で入力を読みながら検索したときは行を所有する
+#[derive(Debug, PartialEq, Eq)]
+pub struct Match<'a> {
+    /// 1始まりの行番号
+    pub line_number: usize,
+    /// ファイル先頭から行頭までのバイトオフセット
+    pub byte_offset: usize,
+    pub line: Cow<'a, str>,
+    /// 行内でマッチした範囲(バイト単位)
+    pub span: Range<usize>,
+}
+
+/// `str::lines`と同じ規則で行を分割し、行番号と行頭のバイトオフセットを付けて返す
+fn lines_with_offsets(contents: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    let mut offset = 0;
+    contents.split_inclusive('\n').enumerate().map(move |(i, raw)| {
+        let start = offset;
+        offset += raw.len();
+        let line = raw.strip_suffix('\n').unwrap_or(raw);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        (i + 1, start, line)
+    })
 }
-impl Config {
-    pub fn new(mut args: std::env::Args) ->  Result<Config, &'static str> { // argsイテレータの所有権を奪い可変で参照する
-        args.next();
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get first param: querystring"),
-        };
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get second param: filename"),
-        };
-        let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
-        let config = Config { query, filename, case_sensitive };
-        Ok(config)
+
+fn search_by<'a, M: Matcher + ?Sized>(contents: &'a str, matcher: &M) -> Vec<Match<'a>> {
+    lines_with_offsets(contents)
+        .filter_map(|(line_number, byte_offset, line)| {
+            matcher.find_at(line, 0).map(|span| Match { line_number, byte_offset, line: Cow::Borrowed(line), span })
+        })
+        .collect()
+}
+
+pub fn search<'a>(query: &str, contents: &'a str) -> Vec<Match<'a>> {
+    search_by(contents, query)
+}
+
+pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<Match<'a>> {
+    search_by(contents, &CaseInsensitive::new(query))
+}
+
+pub fn search_regex<'a>(pattern: &Regex, contents: &'a str) -> Vec<Match<'a>> {
+    search_by(contents, pattern)
+}
+
+/// マッチしなかった行を範囲が空の`Match`として返す
+pub fn search_inverted<'a>(matches: &[Match], contents: &'a str) -> Vec<Match<'a>> {
+    let mut matched = matches.iter().map(|m| m.line_number).peekable();
+    lines_with_offsets(contents)
+        .filter(|(line_number, _, _)| matched.next_if_eq(line_number).is_none())
+        .map(|(line_number, byte_offset, line)| Match { line_number, byte_offset, line: Cow::Borrowed(line), span: 0..0 })
+        .collect()
+}
+
+/// 入力を1行ずつ読みながら`config`どおりに検索し、前後の行や強調表示を含めて出力する。戻り値は選択された行数
+/// 行の選択は`Searcher`と同じで、UTF-8として不正なバイト列は置換文字に置き換えて扱う
+/// `show_path`が真なら各行の先頭に`path`を付ける
+pub fn print_matches<R: BufRead, W: Write>(
+    reader: R,
+    out: &mut W,
+    path: &Path,
+    show_path: bool,
+    config: &Config,
+) -> io::Result<usize> {
+    if let Some(max_distance) = config.fuzzy {
+        return search_fuzzy(reader, out, path, show_path, config, max_distance);
+    }
+    let searcher = Searcher::new(PatternMatcher::new(config)).invert_match(config.invert_match);
+    let quiet = config.count || config.files_with_matches;
+    // 強調表示・JSON出力・置換のときだけ行内のすべてのマッチ範囲を求める
+    let need_spans = config.json || config.color == ColorChoice::Always || config.replace.is_some();
+    let mut printer = ContextPrinter::new(out, path, show_path, config);
+    let mut spans = Vec::new();
+    let mut lines = LineReader::new(reader);
+    let mut selected = 0;
+    while let Some(line) = lines.next_line()? {
+        let line_number = line.number;
+        let line = line.text();
+        if searcher.select(&line).is_some() {
+            selected += 1;
+            if config.files_with_matches {
+                break;
+            }
+            if !quiet {
+                spans.clear();
+                if need_spans && !config.invert_match {
+                    searcher.matcher().find_all(&line, &mut spans);
+                }
+                match replace::preview(&line, &spans, config) {
+                    Some(replaced) => printer.matched(line_number, &replaced, &[])?,
+                    None => printer.matched(line_number, &line, &spans)?,
+                }
+            }
+        } else if !quiet {
+            printer.unmatched(line_number, &line)?;
+        }
+    }
+    Ok(selected)
+}
+
+/// `--fuzzy`では入力ごとにマッチした行を集め、編集距離の小さい順(同じなら行番号順)に出力する
+/// 順位を付けるためにマッチした行だけはメモリに保持する
+fn search_fuzzy<R: BufRead, W: Write>(
+    reader: R,
+    out: &mut W,
+    path: &Path,
+    show_path: bool,
+    config: &Config,
+    max_distance: usize,
+) -> io::Result<usize> {
+    let mut hits = Vec::new();
+    let mut lines = LineReader::new(reader);
+    while let Some(line) = lines.next_line()? {
+        let line_number = line.number;
+        let line = line.text();
+        let best = config
+            .patterns
+            .iter()
+            .enumerate()
+            .map(|(i, pattern)| (fuzzy_find(pattern, &line, config.case_sensitive), i))
+            .min_by_key(|((distance, _), _)| *distance);
+        if let Some(((distance, span), pattern)) = best.filter(|((distance, _), _)| *distance <= max_distance) {
+            if config.files_with_matches {
+                return Ok(1);
+            }
+            hits.push((distance, line_number, line.into_owned(), (span, pattern)));
+        }
     }
+    if !config.count {
+        hits.sort_by_key(|(distance, line_number, _, _)| (*distance, *line_number));
+        let mut printer = ContextPrinter::new(out, path, show_path, config);
+        for (distance, line_number, line, hit) in &hits {
+            printer.set_distance(*distance);
+            printer.matched(*line_number, line, std::slice::from_ref(hit))?;
+        }
+    }
+    Ok(hits.len())
 }
 
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    contents.lines().filter(|line| line.contains(query)).collect()
+/// NULバイトを含むものはバイナリファイルとみなす
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
 }
 
-pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let mut results = Vec::new();
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query.to_lowercase()) { results.push(line); }
+/// ディレクトリを再帰的に辿り、.gitignore/.ignoreで除外されないファイルを列挙する
+/// 隠しファイルも対象にするが、`.git`ディレクトリと索引ファイルは除く
+/// 読めないサブディレクトリなどのエラーは集めて返し、残りのファイルは列挙を続ける
+pub fn walk(dir: &Path) -> (Vec<PathBuf>, Vec<Error>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    let walker = WalkBuilder::new(dir)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.depth() == 0 || (entry.file_name() != ".git" && entry.file_name() != INDEX_FILE))
+        .build();
+    for entry in walker {
+        match entry {
+            Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => files.push(entry.into_path()),
+            Ok(_) => {}
+            Err(e) => errors.push(Error::from(e)),
+        }
     }
+    files.sort();
+    (files, errors)
+}
 
-    results
+/// 1入力分の検索結果を`-c`/`-l`の指定に応じた形式で出力する。戻り値は選択された行数
+fn report<R: BufRead, W: Write>(out: &mut W, reader: R, path: &Path, show_path: bool, config: &Config) -> io::Result<usize> {
+    let selected = print_matches(reader, out, path, show_path, config)?;
+    if config.files_with_matches {
+        if selected > 0 {
+            writeln!(out, "{}", path.display())?;
+        }
+    } else if config.count {
+        if show_path {
+            write!(out, "{}:", path.display())?;
+        }
+        writeln!(out, "{}", selected)?;
+    }
+    Ok(selected)
 }
 
+/// `skip_binary`ならgrepと同様に先頭のバッファだけを見てバイナリかどうかを判定し、バイナリなら読み飛ばす
+fn report_text<R: BufRead, W: Write>(
+    out: &mut W,
+    mut reader: R,
+    path: &Path,
+    show_path: bool,
+    skip_binary: bool,
+    config: &Config,
+) -> io::Result<usize> {
+    if skip_binary && is_binary(reader.fill_buf()?) {
+        return Ok(0);
+    }
+    report(out, reader, path, show_path, config)
+}
 
-pub fn run(config: Config) -> Result<(), Box<Error>> {
-    let mut f = File::open(config.filename)?;
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
-    
-    let results = if config.case_sensitive { 
-        search(&config.query, &contents)
-    }else { 
-        search_case_insensitive(&config.query, &contents) 
-    };
-    for line in results {
-        println!("{}", line);
+/// 検索対象の1入力。パスが`-`のときは標準入力を表す
+struct Target {
+    path: PathBuf,
+    /// ディレクトリを辿って見つけたファイルはバイナリなら読み飛ばす
+    skip_binary: bool,
+}
+
+fn search_target<W: Write>(out: &mut W, target: &Target, show_path: bool, config: &Config) -> Result<usize, Error> {
+    if target.path == Path::new("-") {
+        if config.in_place {
+            return Err(Error::InvalidArgument("Standard input cannot be rewritten in place".to_string()));
+        }
+        let path = Path::new("(standard input)");
+        return report(out, io::stdin().lock(), path, show_path, config).map_err(|e| Error::from_io(path, e));
+    }
+    if let Some(format) = archive::Format::detect(&target.path) {
+        if config.in_place || config.dry_run {
+            // ディレクトリを辿って見つけた圧縮ファイルは書き換えの対象にしない
+            if target.skip_binary {
+                return Ok(0);
+            }
+            let message = format!("{}: Compressed files and archives cannot be rewritten", target.path.display());
+            return Err(Error::InvalidArgument(message));
+        }
+        return archive::search(out, &target.path, format, show_path, target.skip_binary, config);
+    }
+    let file = File::open(&target.path).map_err(|e| Error::from_io(&target.path, e))?;
+    let mut reader = BufReader::with_capacity(64 * 1024, file);
+    // grepと同様に先頭のバッファだけを見てバイナリかどうかを判定する
+    let binary = reader.fill_buf().map_err(|e| Error::from_io(&target.path, e))?;
+    if target.skip_binary && is_binary(binary) {
+        return Ok(0);
+    }
+    if config.in_place || config.dry_run {
+        return replace::rewrite(out, reader, &target.path, config);
+    }
+    report(out, reader, &target.path, show_path, config).map_err(|e| Error::from_io(&target.path, e))
+}
+
+/// `run`の結果。grepと同じく、失敗した入力があっても残りの入力は検索する
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// 1行でも選択されたか
+    pub matched: bool,
+    /// 読めなかった入力があったか。エラーは見つけた時点で標準エラー出力に書いてある
+    pub had_errors: bool,
+}
+
+impl Status {
+    /// 1つの入力についてのエラーを報告して続ける。出力先が閉じられたときだけは続けても無駄なので返す
+    fn report<W: Write>(&mut self, out: &mut W, err: Error) -> Result<(), Error> {
+        if is_broken_pipe(&err) {
+            return Err(err);
+        }
+        // それまでの出力より後にエラーが表示されるようにする
+        out.flush()?;
+        eprintln!("minigrep: {}", err);
+        self.had_errors = true;
+        Ok(())
+    }
+}
+
+fn is_broken_pipe(err: &Error) -> bool {
+    matches!(err, Error::Io(_, e) if e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// 検索を実行する。出力できなくなったときだけ`Err`を返す
+pub fn run(mut config: Config) -> Result<Status, Error> {
+    let stdout = io::stdout();
+    if config.json {
+        config.color = ColorChoice::Never;
+    } else if config.color == ColorChoice::Auto {
+        // 端末に出力するときだけ色を付ける
+        config.color = if stdout.is_terminal() { ColorChoice::Always } else { ColorChoice::Never };
+    }
+    // 並列検索ではワーカーが出力するので、標準出力をロックしたままにしない
+    let mut out = BufWriter::new(stdout);
+    let mut status = Status::default();
+    if config.command == Command::Index {
+        for dir in &config.filenames {
+            match Index::update(Path::new(dir)) {
+                Ok(stats) => writeln!(
+                    out,
+                    "{}: indexed {} files ({} updated, {} removed)",
+                    dir, stats.files, stats.updated, stats.removed
+                )?,
+                Err(e) => status.report(&mut out, e)?,
+            }
+        }
+        out.flush()?;
+        status.matched = !status.had_errors;
+        return Ok(status);
+    }
+
+    let query_trigrams = index::query_trigrams(&config);
+    let mut targets = Vec::new();
+    for filename in &config.filenames {
+        let path = Path::new(filename);
+        if path.is_dir() {
+            let (mut files, errors) = walk(path);
+            for e in errors {
+                status.report(&mut out, e)?;
+            }
+            // 索引があれば、クエリを含み得ないファイルを開く前に除外する
+            if let Some(query) = &query_trigrams {
+                match Index::load(path) {
+                    Ok(Some(index)) => files.retain(|file| index.is_candidate(path, file, query)),
+                    Ok(None) => {}
+                    // 読めない索引は使わずにすべてのファイルを検索する
+                    Err(e) => status.report(&mut out, e)?,
+                }
+            }
+            targets.extend(files.into_iter().map(|path| Target { path, skip_binary: true }));
+        } else {
+            targets.push(Target { path: path.to_path_buf(), skip_binary: false });
+        }
+    }
+    // 複数ファイルかディレクトリを検索する場合は各行にパスを付ける
+    let show_path = config.filenames.len() > 1 || config.filenames.iter().any(|f| Path::new(f).is_dir());
+
+    let jobs = parallel::effective_jobs(config.jobs);
+    let mut selected = 0;
+    if jobs == 1 || targets.len() <= 1 {
+        // 1ファイルだけなら出力をためずにそのまま流す
+        for target in &targets {
+            match search_target(&mut out, target, show_path, &config) {
+                Ok(n) => selected += n,
+                Err(e) => status.report(&mut out, e)?,
+            }
+        }
+    } else {
+        // 入力順で先頭のファイルの出力はそのまま書き出し、先に終わった後ろのファイルの出力だけをためておく
+        let output = parallel::OrderedOutput::new(&mut out);
+        let indexed: Vec<(usize, &Target)> = targets.iter().enumerate().collect();
+        parallel::for_each_ordered(&indexed, jobs, |&(i, target)| {
+            let mut writer = output.writer(i);
+            let result = search_target(&mut writer, target, show_path, &config);
+            let finished = writer.finish().map_err(Error::from);
+            result.and_then(|n| finished.map(|()| n))
+        }, |result| match result {
+            Ok(n) => {
+                selected += n;
+                Ok(())
+            }
+            Err(e) => {
+                output.flush()?;
+                status.report(&mut io::sink(), e)
+            }
+        })?;
     }
-    Ok(())
+    out.flush()?;
+    status.matched = selected > 0;
+    Ok(status)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn lines<'a>(matches: &'a [Match]) -> Vec<&'a str> {
+        matches.iter().map(|m| m.line.as_ref()).collect()
+    }
+
     #[test]
     fn case_sensitive() {
         let query = "duct";
@@ -67,7 +394,7 @@ Rust:
 safe, fast, productive.
 Pick three.
 Duct tape.";
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(vec!["safe, fast, productive."], lines(&search(query, contents)));
     }
 
     #[test]
@@ -78,6 +405,171 @@ Rust:
 safe, fast, productive.
 Pick three.
 Trust me.";
-        assert_eq!( vec!["Rust:", "Trust me."], search_case_insensitive(query, contents));
+        assert_eq!( vec!["Rust:", "Trust me."], lines(&search_case_insensitive(query, contents)));
+    }
+
+    #[test]
+    fn regex() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+        let anchored = Regex::new(r"^[A-Z]\w+:$").unwrap();
+        assert_eq!(vec!["Rust:"], lines(&search_regex(&anchored, contents)));
+        let alternation = Regex::new(r"three|me\.$").unwrap();
+        assert_eq!(vec!["Pick three.", "Trust me."], lines(&search_regex(&alternation, contents)));
+    }
+
+    #[test]
+    fn match_positions() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.";
+        assert_eq!(
+            vec![Match { line_number: 2, byte_offset: 7, line: "safe, fast, productive.".into(), span: 12..16 }],
+            search("prod", contents)
+        );
+        let matches = search_case_insensitive("THREE", contents);
+        assert_eq!((3, 31, 5..10), (matches[0].line_number, matches[0].byte_offset, matches[0].span.clone()));
+    }
+
+    fn test_config(line_number: bool, before_context: usize, after_context: usize) -> Config {
+        Config { case_sensitive: true, line_number, before_context, after_context, ..Config::default() }
+    }
+
+    fn search_to_string(contents: &[u8], path: Option<&Path>, config: &Config) -> String {
+        let mut out = Vec::new();
+        let show_path = path.is_some();
+        let path = path.unwrap_or_else(|| Path::new("-"));
+        print_matches(contents, &mut out, path, show_path, config).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn context_lines() {
+        let contents = b"a\nmatch\nb\nc\nd\ne\nmatch\nmatch\nf";
+        let mut config = test_config(true, 1, 1);
+        config.patterns = vec!["match".to_string()];
+        assert_eq!("1-a\n2:match\n3-b\n--\n6-e\n7:match\n8:match\n9-f\n", search_to_string(contents, None, &config));
+
+        let mut config = test_config(false, 0, 3);
+        config.patterns = vec!["match".to_string()];
+        assert_eq!("match\nb\nc\nd\n--\nmatch\nmatch\nf\n", search_to_string(contents, None, &config));
+
+        let mut config = test_config(true, 0, 0);
+        config.patterns = vec!["match".to_string()];
+        assert_eq!("poem.txt:2:match\npoem.txt:7:match\npoem.txt:8:match\n", search_to_string(contents, Some(Path::new("poem.txt")), &config));
+    }
+
+    #[test]
+    fn streaming_handles_invalid_utf8_and_counts() {
+        let contents = b"Rust:\r\nsaf\xffe, fast, productive.\nPick three.\n";
+        let mut config = test_config(false, 0, 0);
+        config.patterns = vec!["fast".to_string()];
+        assert_eq!("saf\u{fffd}e, fast, productive.\n", search_to_string(contents, None, &config));
+
+        config.count = true;
+        config.invert_match = true;
+        let mut out = Vec::new();
+        assert_eq!(2, print_matches(&contents[..], &mut out, Path::new("-"), false, &config).unwrap());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn color_highlights_every_match() {
+        let mut config = test_config(true, 0, 0);
+        config.pattern = Some(Regex::new("^o|o").unwrap());
+        config.color = ColorChoice::Always;
+        assert_eq!(
+            "\x1b[35mpoem.txt\x1b[0m\x1b[36m:\x1b[0m\x1b[32m1\x1b[0m\x1b[36m:\x1b[0m\x1b[1;31mo\x1b[0mg\x1b[1;31mo\x1b[0m!\n",
+            search_to_string(b"ogo!\nbaa", Some(Path::new("poem.txt")), &config)
+        );
+
+        config.pattern = Some(Regex::new("x*").unwrap());
+        config.line_number = false;
+        assert_eq!("abc\n", search_to_string(b"abc", None, &config));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn json_output() {
+        let mut config = test_config(false, 1, 1);
+        config.patterns = vec!["you".to_string()];
+        config.json = true;
+        let output = search_to_string(b"I'm nobody! Who are you?\nAre you nobody, too?\n", Some(Path::new("poem.txt")), &config);
+        let values: Vec<serde_json::Value> = output.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(2, values.len());
+        assert_eq!(
+            serde_json::json!({ "path": "poem.txt", "line": 1, "column": 21, "text": "I'm nobody! Who are you?", "match": "you", "pattern": "you" }),
+            values[0]
+        );
+        assert_eq!((2, 5), (values[1]["line"].as_u64().unwrap(), values[1]["column"].as_u64().unwrap()));
+    }
+
+    #[test]
+    fn replace_preview() {
+        let mut config = test_config(true, 0, 0);
+        config.patterns = vec!["nobody".to_string()];
+        config.replace = Some("somebody".to_string());
+        assert_eq!("1:I'm somebody! Who are you?\n", search_to_string(b"I'm nobody! Who are you?\nfrog", None, &config));
+    }
+
+    #[test]
+    fn fuzzy_results_are_ranked() {
+        let mut config = test_config(true, 0, 0);
+        config.patterns = vec!["nobody".to_string()];
+        config.fuzzy = Some(2);
+        let contents = b"Then there's a pair of us\nsomebody\nI'm nobody!\nno body\n";
+        assert_eq!("3:I'm nobody!\n4:no body\n2:somebody\n", search_to_string(contents, None, &config));
+
+        config.json = true;
+        let output = search_to_string(contents, None, &config);
+        let first: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!((0, 5), (first["distance"].as_u64().unwrap(), first["column"].as_u64().unwrap()));
+    }
+
+    #[test]
+    fn invert_match() {
+        let contents = "Rust:\nsafe, fast, productive.\nPick three.";
+        let inverted = search_inverted(&search("Rust", contents), contents);
+        assert_eq!(vec!["safe, fast, productive.", "Pick three."], lines(&inverted));
+        assert_eq!((2, 6), (inverted[0].line_number, inverted[0].byte_offset));
+    }
+
+    #[test]
+    fn walk_honours_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::create_dir(dir.path().join("build")).unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "build/\n").unwrap();
+        std::fs::write(dir.path().join(".ignore"), "*.log\n").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "Rust").unwrap();
+        std::fs::write(dir.path().join("sub/b.txt"), "Rust").unwrap();
+        std::fs::write(dir.path().join("build/c.txt"), "Rust").unwrap();
+        std::fs::write(dir.path().join("d.log"), "Rust").unwrap();
+        std::fs::create_dir_all(dir.path().join(".git/refs")).unwrap();
+        std::fs::write(dir.path().join(".git/HEAD"), "Rust").unwrap();
+        std::fs::write(dir.path().join(".env"), "Rust").unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE), "Rust").unwrap();
+
+        let (files, errors) = walk(dir.path());
+        assert!(errors.is_empty());
+        let expected = [".env", ".gitignore", ".ignore", "a.txt", "sub/b.txt"];
+        assert_eq!(expected.iter().map(|f| dir.path().join(f)).collect::<Vec<_>>(), files);
+    }
+
+    #[test]
+    fn walk_collects_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "Rust").unwrap();
+        let (files, errors) = walk(&dir.path().join("missing"));
+        assert!(files.is_empty());
+        assert_eq!(1, errors.len());
+        let (files, errors) = walk(dir.path());
+        assert_eq!((vec![dir.path().join("a.txt")], 0), (files, errors.len()));
+    }
+
+    #[test]
+    fn binary_detection() {
+        assert!(is_binary(b"ELF\0\x01"));
+        assert!(!is_binary("safe, fast, productive.".as_bytes()));
+    }
+}
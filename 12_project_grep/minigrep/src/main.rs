@@ -4,15 +4,33 @@ use std::env;
 use std::process;
 use minigrep::Config;
 
+// grepと同じ終了コード
+const EXIT_MATCH: i32 = 0;
+const EXIT_NO_MATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
 fn main() {
     let config = Config::new(env::args()).unwrap_or_else(|err| {
         eprintln!("{}", err);
-        process::exit(1);
+        eprintln!("{}", minigrep::USAGE);
+        process::exit(EXIT_ERROR);
     });
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error: {}", e);
-        process::exit(1);
+    if config.help {
+        println!("{}", minigrep::USAGE);
+        return;
+    }
+    if config.version {
+        println!("minigrep {}", minigrep::VERSION);
+        return;
+    }
+    // grepと同じく、読めなかった入力があればマッチした行があってもエラーとして終わる
+    match minigrep::run(config) {
+        Ok(status) if status.had_errors => process::exit(EXIT_ERROR),
+        Ok(status) if status.matched => process::exit(EXIT_MATCH),
+        Ok(_) => process::exit(EXIT_NO_MATCH),
+        Err(e) => {
+            eprintln!("Application error: {}", e);
+            process::exit(EXIT_ERROR);
+        }
     }
 }
-
-
@@ -0,0 +1,328 @@
+use std::ops::Range;
+
+use aho_corasick::{AhoCorasick, Input, MatchKind};
+use regex::{Regex, RegexBuilder};
+
+use crate::config::{build_regex, whole_word};
+use crate::{Config, Matcher};
+
+/// 行内でマッチした範囲と、マッチしたパターンの番号(`Config::patterns`の添字)
+pub(crate) type Hit = (Range<usize>, usize);
+
+type LineFinder<'a> = Box<dyn Fn(&str, usize) -> Option<Hit> + 'a>;
+
+/// 文字列の先頭にマッチすれば、そのマッチの長さを返す関数
+type PrefixMatcher<'a> = Box<dyn Fn(&str) -> Option<usize> + 'a>;
+
+/// 大文字小文字を区別しない固定文字列の検索
+/// 行とクエリがどちらもASCIIならバイト単位で比較し、行ごとのメモリ確保をしない
+/// それ以外はUnicodeの単純ケースフォールディングに従う正規表現で探すので、
+/// 小文字化で長さが変わる文字(`İ`など)やKelvin記号`K`のような文字も正しく扱える
+pub struct CaseInsensitive {
+    query: String,
+    pattern: Regex,
+}
+
+impl CaseInsensitive {
+    pub fn new(query: &str) -> CaseInsensitive {
+        let pattern = RegexBuilder::new(&regex::escape(query))
+            .case_insensitive(true)
+            .build()
+            .expect("escaped query is always a valid pattern");
+        CaseInsensitive { query: query.to_string(), pattern }
+    }
+
+    pub fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        if self.query.is_ascii() && line.is_ascii() {
+            find_ascii_ignore_case(line.as_bytes(), self.query.as_bytes(), start)
+        } else {
+            self.pattern.find_at(line, start).map(|m| m.range())
+        }
+    }
+}
+
+fn find_ascii_ignore_case(haystack: &[u8], needle: &[u8], start: usize) -> Option<Range<usize>> {
+    if needle.is_empty() {
+        return Some(start..start);
+    }
+    haystack[start..]
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+        .map(|i| start + i..start + i + needle.len())
+}
+
+/// 正規表現の`\w`と同じく、英字・結合文字・数字・連結句読点(`_`など)を単語を構成する文字とみなす
+/// `-w`の結果を`-E '\bcafe\b'`と揃えるため、判定は正規表現エンジンのものをそのまま使う
+fn is_word_char(c: char) -> bool {
+    regex_syntax::is_word_character(c)
+}
+
+/// マッチ範囲の前後が行頭・行末か単語を構成しない文字であるか
+fn is_whole_word(line: &str, span: &Range<usize>) -> bool {
+    let before = line[..span.start].chars().next_back();
+    let after = line[span.end..].chars().next();
+    !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+}
+
+/// 複数の固定文字列のうちどれか1つを探す
+/// 行頭に近いものを優先し、同じ位置から始まるものは先に指定されたパターンを選ぶ
+/// 大文字小文字を区別しないときは、行とパターンがすべてASCIIならAho-Corasick法で、
+/// そうでなければUnicodeのケースフォールディングに従う正規表現で探す
+struct MultiLiteral {
+    automaton: Option<AhoCorasick>,
+    fallback: Option<(Regex, Vec<CaseInsensitive>)>,
+}
+
+impl MultiLiteral {
+    fn new(patterns: &[String], case_sensitive: bool) -> MultiLiteral {
+        let all_ascii = patterns.iter().all(|p| p.is_ascii());
+        let automaton = (case_sensitive || all_ascii).then(|| {
+            AhoCorasick::builder()
+                .match_kind(MatchKind::LeftmostFirst)
+                .ascii_case_insensitive(!case_sensitive)
+                .build(patterns)
+                .expect("literal patterns always build")
+        });
+        let fallback = (!case_sensitive).then(|| {
+            let alternation: Vec<String> = patterns.iter().map(|p| regex::escape(p)).collect();
+            let combined = RegexBuilder::new(&alternation.join("|"))
+                .case_insensitive(true)
+                .build()
+                .expect("escaped patterns are always valid");
+            (combined, patterns.iter().map(|p| CaseInsensitive::new(p)).collect())
+        });
+        MultiLiteral { automaton, fallback }
+    }
+
+    fn find_at(&self, line: &str, start: usize) -> Option<Hit> {
+        if let Some(automaton) = &self.automaton {
+            if self.fallback.is_none() || line.is_ascii() {
+                let m = automaton.find(Input::new(line).span(start..line.len()))?;
+                return Some((m.range(), m.pattern().as_usize()));
+            }
+        }
+        let (combined, matchers) = self.fallback.as_ref()?;
+        let m = combined.find_at(line, start)?;
+        let which = matchers
+            .iter()
+            .position(|matcher| matcher.find_at(line, m.start()).is_some_and(|r| r.start == m.start()));
+        Some((m.range(), which.unwrap_or(0)))
+    }
+}
+
+/// `Config`のパターンを`-E`・`-i`・`-w`の指定どおりに照合する`Matcher`
+/// コマンドラインと同じ照合を`Searcher`で使いたいときは`Config`からこれを作る
+pub struct PatternMatcher<'a> {
+    find: LineFinder<'a>,
+}
+
+impl<'a> PatternMatcher<'a> {
+    /// パターンのコンパイルは一度だけ行い、以降の行ではそれを使い回す
+    pub fn new(config: &'a Config) -> PatternMatcher<'a> {
+        PatternMatcher { find: line_finder(config) }
+    }
+
+    /// `start`バイト目以降で最初のマッチ範囲と、どのパターンにマッチしたかを返す
+    pub(crate) fn find_hit(&self, line: &str, start: usize) -> Option<Hit> {
+        (self.find)(line, start)
+    }
+
+    /// 行内の重ならないマッチ範囲をすべて`hits`に集める
+    pub(crate) fn find_all(&self, line: &str, hits: &mut Vec<Hit>) {
+        let mut start = 0;
+        while start <= line.len() {
+            let (span, pattern) = match self.find_hit(line, start) {
+                Some(hit) => hit,
+                None => break,
+            };
+            // 空文字列にマッチしたときは次の文字へ進めて無限ループを避ける
+            start = if span.is_empty() {
+                line[span.end..].chars().next().map_or(line.len() + 1, |c| span.end + c.len_utf8())
+            } else {
+                span.end
+            };
+            if !span.is_empty() {
+                hits.push((span, pattern));
+            }
+        }
+    }
+}
+
+impl Matcher for PatternMatcher<'_> {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        self.find_hit(line, start).map(|(span, _)| span)
+    }
+}
+
+/// 設定に応じて1行の指定位置以降からマッチ範囲を探す関数を作る
+fn line_finder(config: &Config) -> LineFinder<'_> {
+    let find: LineFinder = if config.patterns.is_empty() && config.pattern.is_none() {
+        // 空のパターンファイルを渡されたとき
+        Box::new(|_, _| None)
+    } else if let Some(pattern) = &config.pattern {
+        if config.patterns.len() <= 1 {
+            // `find_at`は`^`などのアンカーを行頭基準で判定する
+            Box::new(move |line, start| pattern.find_at(line, start).map(|m| (m.range(), 0)))
+        } else {
+            // まとめた正規表現でマッチ範囲を求め、どのパターンに由来するかは個別の正規表現で確かめる
+            // `-w`ならまとめた正規表現と同じく、個別の正規表現にも単語の境界の条件を含める
+            let individual: Vec<Regex> = config
+                .patterns
+                .iter()
+                .map(|p| if config.word_regexp { whole_word(p) } else { p.clone() })
+                .map(|p| build_regex(&p, config.case_sensitive).expect("patterns are validated by Config::new"))
+                .collect();
+            Box::new(move |line, start| {
+                let m = pattern.find_at(line, start)?;
+                let which = individual
+                    .iter()
+                    .position(|re| re.find_at(line, m.start()).is_some_and(|n| n.start() == m.start()));
+                Some((m.range(), which.unwrap_or(0)))
+            })
+        }
+    } else if let [query] = config.patterns.as_slice() {
+        if config.case_sensitive {
+            Box::new(move |line, start| {
+                line[start..].find(query.as_str()).map(|i| (start + i..start + i + query.len(), 0))
+            })
+        } else {
+            let matcher = CaseInsensitive::new(query);
+            Box::new(move |line, start| matcher.find_at(line, start).map(|r| (r, 0)))
+        }
+    } else {
+        let matcher = MultiLiteral::new(&config.patterns, config.case_sensitive);
+        Box::new(move |line, start| matcher.find_at(line, start))
+    };
+    // 正規表現は`Config::new`で単語の境界の条件を含めてコンパイルしてある
+    if !config.word_regexp || config.pattern.is_some() {
+        return find;
+    }
+    // `-w`の固定文字列では、単語の境界に挟まれていない候補を見つけたら同じ位置から始まる他のパターンを順に試す
+    // `foo`と`foobar`のように重なるパターンで、先に選ばれた短い方が境界の条件を満たさないことがあるため
+    // どれも満たさなければ次の文字から探し直す
+    let prefixes: Vec<PrefixMatcher> = config.patterns.iter().map(|p| prefix_matcher(p, config.case_sensitive)).collect();
+    Box::new(move |line, mut start| loop {
+        let hit = find(line, start)?;
+        if is_whole_word(line, &hit.0) {
+            return Some(hit);
+        }
+        let at = hit.0.start;
+        let other = prefixes.iter().enumerate().find_map(|(i, prefix)| {
+            let span = at..at + prefix(&line[at..])?;
+            is_whole_word(line, &span).then_some((span, i))
+        });
+        if other.is_some() {
+            return other;
+        }
+        start = at + line[at..].chars().next().map_or(1, char::len_utf8);
+        if start > line.len() {
+            return None;
+        }
+    })
+}
+
+/// 固定文字列`query`が先頭にマッチするか確かめる関数を作る
+fn prefix_matcher(query: &str, case_sensitive: bool) -> PrefixMatcher<'_> {
+    if case_sensitive {
+        Box::new(move |text| text.starts_with(query).then_some(query.len()))
+    } else {
+        let pattern = RegexBuilder::new(&format!("^(?:{})", regex::escape(query)))
+            .case_insensitive(true)
+            .build()
+            .expect("escaped query is always a valid pattern");
+        Box::new(move |text| pattern.find(text).map(|m| m.end()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ascii_case_insensitive() {
+        let matcher = CaseInsensitive::new("rUsT");
+        assert_eq!(Some(1..5), matcher.find_at("Trust me.", 0));
+        assert_eq!(Some(9..13), matcher.find_at("RUST and rust", 1));
+        assert_eq!(None, matcher.find_at("Rus t", 0));
+    }
+
+    #[test]
+    fn unicode_case_insensitive() {
+        let matcher = CaseInsensitive::new("σίσυφος");
+        assert_eq!(Some(3..17), matcher.find_at("ο ΣΊΣΥΦΟΣ", 0));
+        // Kelvin記号(U+212A)は`k`と同じ文字として扱う
+        assert_eq!(Some(0..8), CaseInsensitive::new("kelvin").find_at("\u{212A}elvin", 0));
+        assert_eq!(Some(3..11), CaseInsensitive::new("straße").find_at("in STRAẞE", 0));
+    }
+
+    #[test]
+    fn whole_word() {
+        let config = Config { patterns: vec!["rust".to_string()], case_sensitive: true, word_regexp: true, ..Config::default() };
+        let matcher = PatternMatcher::new(&config);
+        let find = |line| matcher.find_hit(line, 0);
+        assert_eq!(Some((20..24, 0)), find("trust rust_ rustの rust!"));
+        assert_eq!(None, find("trusty rusty"));
+        assert_eq!(Some((0..4, 0)), find("rust"));
+        // 分解された`é`の結合文字(U+0301)や連結句読点`‿`も単語の一部
+        assert_eq!(None, find("caf rust\u{301} rust\u{203f}"));
+
+        let config = Config { pattern: Some(Regex::new(&crate::config::whole_word("é+")).unwrap()), word_regexp: true, ..Config::default() };
+        assert_eq!(Some(9..13), PatternMatcher::new(&config).find_at("caféé, éé", 0));
+    }
+
+    #[test]
+    fn whole_word_overlapping_patterns() {
+        // 先に指定した`foo`が単語の途中でも、同じ位置から始まる`foobar`は単語としてマッチする
+        for case_sensitive in [true, false] {
+            let mut config = multi(&["foo", "foobar"], case_sensitive);
+            config.word_regexp = true;
+            let matcher = PatternMatcher::new(&config);
+            assert_eq!(Some((0..6, 1)), matcher.find_hit("foobar baz", 0));
+            assert_eq!(Some((7..10, 0)), matcher.find_hit("foobaz foo", 0));
+            assert_eq!(None, matcher.find_hit("foobarbaz", 0));
+        }
+        let mut config = multi(&["foo", "ΣΊΣ", "σίσυφος"], false);
+        config.word_regexp = true;
+        assert_eq!(Some((2..16, 2)), PatternMatcher::new(&config).find_hit("a ΣΊΣΥΦΟΣ", 0));
+
+        let mut config = multi(&["foo|foobar", "baz"], true);
+        config.word_regexp = true;
+        config.pattern = Some(Regex::new(&crate::config::whole_word("(?:foo|foobar)|(?:baz)")).unwrap());
+        let mut hits = Vec::new();
+        PatternMatcher::new(&config).find_all("foobar baz", &mut hits);
+        assert_eq!(vec![(0..6, 0), (7..10, 1)], hits);
+    }
+
+    fn multi(patterns: &[&str], case_sensitive: bool) -> Config {
+        Config { patterns: patterns.iter().map(|p| p.to_string()).collect(), case_sensitive, ..Config::default() }
+    }
+
+    #[test]
+    fn multiple_literals() {
+        let config = multi(&["frog", "bog", "fro"], true);
+        let mut hits = Vec::new();
+        PatternMatcher::new(&config).find_all("a frog in a bog", &mut hits);
+        assert_eq!(vec![(2..6, 0), (12..15, 1)], hits);
+
+        let config = multi(&["BOG", "σίσυφος"], false);
+        let matcher = PatternMatcher::new(&config);
+        assert_eq!(Some((12..15, 0)), matcher.find_hit("a frog in a bog", 0));
+        assert_eq!(Some((2..16, 1)), matcher.find_hit("a ΣΊΣΥΦΟΣ bog", 0));
+    }
+
+    #[test]
+    fn no_patterns_match_nothing() {
+        let find_in = |case_sensitive| PatternMatcher::new(&multi(&[], case_sensitive)).find_hit("ΣΊΣΥΦΟΣ frog", 0);
+        assert_eq!(None, find_in(true));
+        assert_eq!(None, find_in(false));
+    }
+
+    #[test]
+    fn multiple_regexes_report_pattern() {
+        let mut config = multi(&["^a", "b+"], true);
+        config.pattern = Some(Regex::new("(?:^a)|(?:b+)").unwrap());
+        let mut hits = Vec::new();
+        PatternMatcher::new(&config).find_all("abba a", &mut hits);
+        assert_eq!(vec![(0..1, 0), (1..3, 1)], hits);
+    }
+}
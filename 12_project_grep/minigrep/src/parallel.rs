@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// 順番が来ている書き手は、これだけたまるたびに出力先へ書き出す
+const CHUNK_SIZE: usize = 64 * 1024;
+/// 順番待ちの書き手がためておける量。これを超えたら順番が来るまで待つ
+const PENDING_LIMIT: usize = 4 * 1024 * 1024;
+
+/// 並列度の指定が0のときは利用可能なCPU数を使う
+pub(crate) fn effective_jobs(jobs: usize) -> usize {
+    if jobs > 0 {
+        jobs
+    } else {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    }
+}
+
+/// `items`の各要素に`work`を`jobs`本のスレッドで並列に適用し、結果を`items`の順に`sink`へ渡す
+/// 先に終わった結果は手前の要素が終わるまで保持しておくので、出力順は常に入力順と一致する
+/// `sink`がエラーを返したら残りの要素には手を付けずにそのエラーを返す
+pub(crate) fn for_each_ordered<T, R, E, F, S>(items: &[T], jobs: usize, work: F, mut sink: S) -> Result<(), E>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    S: FnMut(R) -> Result<(), E>,
+{
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.min(items.len()) {
+            let sender = sender.clone();
+            let (next, work) = (&next, &work);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() {
+                    break;
+                }
+                if sender.send((i, work(&items[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (i, result) in receiver {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&expected) {
+                expected += 1;
+                if let Err(e) = sink(result) {
+                    // 未着手の要素を残さないようにしてワーカーを止める
+                    next.store(items.len(), Ordering::SeqCst);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// 複数のスレッドが並列に書く出力を、番号順に1つの出力先へ並べる
+/// 先頭の番号の書き手は出力先へ直接書き、それより後の書き手だけが自分の順番までためておく
+/// ためる量には上限があるので、巨大な入力があってもメモリ使用量は書き手の数に比例する程度で収まる
+pub(crate) struct OrderedOutput<W> {
+    state: Mutex<OrderedState<W>>,
+    /// 出力先へ書いてよい番号
+    front: AtomicUsize,
+    turn: Condvar,
+}
+
+struct OrderedState<W> {
+    out: W,
+    /// 順番より先に書き終わった書き手の出力
+    finished: BTreeMap<usize, Vec<u8>>,
+}
+
+impl<W: Write> OrderedOutput<W> {
+    pub(crate) fn new(out: W) -> OrderedOutput<W> {
+        OrderedOutput {
+            state: Mutex::new(OrderedState { out, finished: BTreeMap::new() }),
+            front: AtomicUsize::new(0),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// `index`番目の出力を書く書き手を作る。どの番号も必ず1度だけ作り、使い終わったら捨てること
+    pub(crate) fn writer(&self, index: usize) -> OrderedWriter<'_, W> {
+        OrderedWriter { output: self, index, pending: Vec::new(), done: false }
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.lock().out.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OrderedState<W>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// `OrderedOutput::writer`が返す書き手。捨てたときに自分の出力を締めくくり、次の番号へ順番を渡す
+pub(crate) struct OrderedWriter<'a, W: Write> {
+    output: &'a OrderedOutput<W>,
+    index: usize,
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl<W: Write> OrderedWriter<'_, W> {
+    /// 残りを書き出して順番を渡す。捨てるだけでも同じことをするが、書き込みのエラーはここでしか分からない
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.finish_inner()
+    }
+
+    fn finish_inner(&mut self) -> io::Result<()> {
+        self.done = true;
+        let output = self.output;
+        let mut state = output.lock();
+        if output.front.load(Ordering::SeqCst) != self.index {
+            state.finished.insert(self.index, mem::take(&mut self.pending));
+            return Ok(());
+        }
+        // 書き込みに失敗しても順番は渡す。そうしないと後ろの書き手が待ち続ける
+        let mut result = state.out.write_all(&self.pending);
+        let mut next = self.index + 1;
+        while let Some(buf) = state.finished.remove(&next) {
+            result = result.and_then(|()| state.out.write_all(&buf));
+            next += 1;
+        }
+        output.front.store(next, Ordering::SeqCst);
+        output.turn.notify_all();
+        result
+    }
+
+    /// ためた分を出力先へ書く。順番が来ていなければ、`wait`なら来るまで待ち、そうでなければ何もしない
+    fn drain(&mut self, wait: bool) -> io::Result<()> {
+        let output = self.output;
+        let mut state = output.lock();
+        while output.front.load(Ordering::SeqCst) != self.index {
+            if !wait {
+                return Ok(());
+            }
+            state = output.turn.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+        let result = state.out.write_all(&self.pending);
+        self.pending.clear();
+        result
+    }
+}
+
+impl<W: Write> Write for OrderedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= PENDING_LIMIT {
+            self.drain(true)?;
+        } else if self.pending.len() >= CHUNK_SIZE {
+            self.drain(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// 順番が来るまでは出力先へ書けないので、ためたままにする
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for OrderedWriter<'_, W> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.finish_inner();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_keep_input_order() {
+        let items: Vec<u64> = (0..20).collect();
+        let mut results = Vec::new();
+        let outcome: Result<(), ()> = for_each_ordered(&items, 4, |n| {
+            // 後の要素ほど早く終わるようにして順序の入れ替わりを起こす
+            thread::sleep(Duration::from_millis(20 - n));
+            n * 10
+        }, |r| {
+            results.push(r);
+            Ok(())
+        });
+        assert!(outcome.is_ok());
+        assert_eq!((0..20).map(|n| n * 10).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn ordered_output_buffers_only_later_writers() {
+        let output = OrderedOutput::new(Vec::new());
+        let mut second = output.writer(1);
+        let mut first = output.writer(0);
+        second.write_all(b"second\n").unwrap();
+        second.finish().unwrap();
+        // 先頭の書き手は大きな出力をためずに出力先へ直接書く
+        first.write_all(&vec![b'x'; CHUNK_SIZE]).unwrap();
+        assert_eq!(CHUNK_SIZE, output.lock().out.len());
+        first.write_all(b"\n").unwrap();
+        drop(first);
+        let mut third = output.writer(2);
+        third.write_all(b"third\n").unwrap();
+        third.finish().unwrap();
+
+        let out = output.state.into_inner().unwrap().out;
+        assert_eq!(format!("{}\nsecond\nthird\n", "x".repeat(CHUNK_SIZE)).as_bytes(), &out[..]);
+    }
+
+    #[test]
+    fn writers_past_the_limit_wait_for_their_turn() {
+        let output = OrderedOutput::new(Vec::new());
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut second = output.writer(1);
+                second.write_all(&vec![b'b'; PENDING_LIMIT + 1]).unwrap();
+                second.finish().unwrap();
+            });
+            thread::sleep(Duration::from_millis(20));
+            let mut first = output.writer(0);
+            first.write_all(b"a").unwrap();
+            first.finish().unwrap();
+        });
+        let out = output.state.into_inner().unwrap().out;
+        assert_eq!((PENDING_LIMIT + 2, b'a', b'b'), (out.len(), out[0], out[1]));
+    }
+
+    #[test]
+    fn sink_error_stops_early() {
+        let items: Vec<u32> = (0..100).collect();
+        let mut seen = 0;
+        let outcome = for_each_ordered(&items, 2, |n| *n, |n| {
+            seen += 1;
+            if n == 3 { Err("stop") } else { Ok(()) }
+        });
+        assert_eq!(Err("stop"), outcome);
+        assert_eq!(4, seen);
+    }
+}
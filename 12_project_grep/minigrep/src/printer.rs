@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde_json::json;
+
+use crate::matcher::Hit;
+use crate::{ColorChoice, Config};
+
+// grepの既定の配色に合わせる
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const PATH_COLOR: &str = "\x1b[35m";
+const LINE_NUMBER_COLOR: &str = "\x1b[32m";
+const SEPARATOR_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// 行を1行ずつ受け取り、マッチ行を前後の文脈行とともに出力する
+/// grepと同様にマッチ行は`:`、文脈行は`-`で区切り、隣接しないグループの間には`--`を挟む
+/// 保持するのは直前の`before_context`行だけなので、入力の大きさによらずメモリ使用量は一定
+/// `--json`のときは文脈行を出さず、マッチ1件につき1行のJSONオブジェクトを出力する
+/// オブジェクトにはマッチしたパターンも含める
+pub(crate) struct ContextPrinter<'a, W: Write> {
+    out: W,
+    path: &'a Path,
+    show_path: bool,
+    config: &'a Config,
+    color: bool,
+    before: VecDeque<(usize, String)>,
+    after_remaining: usize,
+    /// 最後に出力した行の行番号(未出力なら0)
+    last_printed: usize,
+    /// `--fuzzy`で次に出力する行の編集距離。JSON出力に含める
+    distance: Option<usize>,
+}
+
+impl<'a, W: Write> ContextPrinter<'a, W> {
+    pub(crate) fn new(out: W, path: &'a Path, show_path: bool, config: &'a Config) -> ContextPrinter<'a, W> {
+        ContextPrinter {
+            out,
+            path,
+            show_path,
+            config,
+            color: config.color == ColorChoice::Always,
+            before: VecDeque::with_capacity(config.before_context),
+            after_remaining: 0,
+            last_printed: 0,
+            distance: None,
+        }
+    }
+
+    pub(crate) fn set_distance(&mut self, distance: usize) {
+        self.distance = Some(distance);
+    }
+
+    /// `spans`は行内でマッチした範囲。`-v`で選ばれた行では空になる
+    pub(crate) fn matched(&mut self, line_number: usize, line: &str, spans: &[Hit]) -> io::Result<()> {
+        if self.config.json {
+            return self.print_json(line_number, line, spans);
+        }
+        let first = self.before.front().map_or(line_number, |(n, _)| *n);
+        let with_context = self.config.before_context > 0 || self.config.after_context > 0;
+        if with_context && self.last_printed > 0 && first > self.last_printed + 1 {
+            self.paint(SEPARATOR_COLOR, "--")?;
+            writeln!(self.out)?;
+        }
+        while let Some((n, context)) = self.before.pop_front() {
+            self.print(n, '-', &context, &[])?;
+        }
+        self.print(line_number, ':', line, spans)?;
+        self.after_remaining = self.config.after_context;
+        Ok(())
+    }
+
+    pub(crate) fn unmatched(&mut self, line_number: usize, line: &str) -> io::Result<()> {
+        if self.config.json {
+            Ok(())
+        } else if self.after_remaining > 0 {
+            self.after_remaining -= 1;
+            self.print(line_number, '-', line, &[])
+        } else {
+            if self.config.before_context > 0 {
+                if self.before.len() == self.config.before_context {
+                    self.before.pop_front();
+                }
+                self.before.push_back((line_number, line.to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    fn print(&mut self, line_number: usize, separator: char, line: &str, spans: &[Hit]) -> io::Result<()> {
+        let separator = separator.to_string();
+        if self.show_path {
+            self.paint(PATH_COLOR, &self.path.display().to_string())?;
+            self.paint(SEPARATOR_COLOR, &separator)?;
+        }
+        if self.config.line_number {
+            self.paint(LINE_NUMBER_COLOR, &line_number.to_string())?;
+            self.paint(SEPARATOR_COLOR, &separator)?;
+        }
+        let mut end = 0;
+        for (span, _) in spans {
+            write!(self.out, "{}", &line[end..span.start])?;
+            self.paint(MATCH_COLOR, &line[span.clone()])?;
+            end = span.end;
+        }
+        writeln!(self.out, "{}", &line[end..])?;
+        self.last_printed = line_number;
+        Ok(())
+    }
+
+    fn paint(&mut self, color: &str, text: &str) -> io::Result<()> {
+        if self.color && !text.is_empty() {
+            write!(self.out, "{}{}{}", color, text, RESET)
+        } else {
+            self.out.write_all(text.as_bytes())
+        }
+    }
+
+    fn print_json(&mut self, line_number: usize, line: &str, spans: &[Hit]) -> io::Result<()> {
+        let path = self.path.to_string_lossy();
+        if spans.is_empty() {
+            let value = json!({ "path": path, "line": line_number, "column": null, "text": line });
+            return writeln!(self.out, "{}", value);
+        }
+        for (span, pattern) in spans {
+            // 列番号は1始まりのバイト位置
+            let mut value = json!({
+                "path": path,
+                "line": line_number,
+                "column": span.start + 1,
+                "text": line,
+                "match": &line[span.clone()],
+                "pattern": self.config.patterns.get(*pattern),
+            });
+            if let Some(distance) = self.distance {
+                value["distance"] = json!(distance);
+            }
+            writeln!(self.out, "{}", value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+
+use regex::Regex;
+use tempfile::NamedTempFile;
+
+use crate::matcher::{Hit, PatternMatcher};
+use crate::searcher::LineReader;
+use crate::{Config, Error};
+
+/// 行内の`spans`を`replacement`に置き換えた文字列を作る
+/// 正規表現モードでは`$1`や`${name}`でキャプチャグループを参照できる
+pub(crate) fn replace_spans(line: &str, spans: &[Hit], replacement: &str, pattern: Option<&Regex>) -> String {
+    let mut replaced = String::with_capacity(line.len());
+    let mut end = 0;
+    for (span, _) in spans {
+        replaced.push_str(&line[end..span.start]);
+        match pattern.and_then(|p| p.captures_at(line, span.start)) {
+            Some(caps) => caps.expand(replacement, &mut replaced),
+            None => replaced.push_str(replacement),
+        }
+        end = span.end;
+    }
+    replaced.push_str(&line[end..]);
+    replaced
+}
+
+/// ファイルの各行に置換を適用する。戻り値は書き換わった行数
+/// `--in-place`では同じディレクトリの一時ファイルに書き出してからrenameで差し替えるので、
+/// 途中で失敗しても元のファイルが中途半端な状態で残ることはない
+/// `--dry-run`ではファイルには触れず、変更内容を差分形式で`out`に出力する
+/// UTF-8として不正な行は壊さないようにそのまま残す
+pub(crate) fn rewrite<R: BufRead, W: Write>(out: &mut W, reader: R, path: &Path, config: &Config) -> Result<usize, Error> {
+    let replacement = config.replace.as_deref().unwrap_or_default();
+    let matcher = PatternMatcher::new(config);
+    let io_error = |e| Error::from_io(path, e);
+
+    let mut temp = if config.dry_run {
+        None
+    } else {
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        Some(BufWriter::new(NamedTempFile::new_in(dir).map_err(io_error)?))
+    };
+    let mut spans = Vec::new();
+    let mut lines = LineReader::new(reader);
+    let mut changed = 0;
+    while let Some(line) = lines.next_line().map_err(io_error)? {
+        let (line_number, body, terminator) = (line.number, line.body, line.terminator);
+        let replaced = match std::str::from_utf8(body) {
+            Ok(line) => {
+                spans.clear();
+                matcher.find_all(line, &mut spans);
+                if spans.is_empty() {
+                    None
+                } else {
+                    Some((line, replace_spans(line, &spans, replacement, config.pattern.as_ref())))
+                }
+            }
+            Err(_) => None,
+        };
+        if let Some((line, replaced)) = &replaced {
+            if config.dry_run {
+                if changed == 0 {
+                    writeln!(out, "--- {}\n+++ {}", path.display(), path.display())?;
+                }
+                writeln!(out, "@@ -{} +{} @@\n-{}\n+{}", line_number, line_number, line, replaced)?;
+            }
+            changed += 1;
+        }
+        if let Some(temp) = temp.as_mut() {
+            match &replaced {
+                Some((_, replaced)) => temp.write_all(replaced.as_bytes()).map_err(io_error)?,
+                None => temp.write_all(body).map_err(io_error)?,
+            }
+            temp.write_all(terminator).map_err(io_error)?;
+        }
+    }
+
+    if let Some(temp) = temp {
+        // 変更がなければ一時ファイルは捨て、元のファイルの更新日時も変えない
+        if changed > 0 {
+            let temp = temp.into_inner().map_err(|e| io_error(e.into_error()))?;
+            let permissions = fs::metadata(path).map_err(io_error)?.permissions();
+            fs::set_permissions(temp.path(), permissions).map_err(io_error)?;
+            temp.persist(path).map_err(|e| io_error(e.error))?;
+        }
+    }
+    Ok(changed)
+}
+
+/// `--replace`だけが指定されたときに、置換後の行を表示用に作る
+pub(crate) fn preview(line: &str, spans: &[Hit], config: &Config) -> Option<String> {
+    let replacement = config.replace.as_deref()?;
+    Some(replace_spans(line, spans, replacement, config.pattern.as_ref()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    fn config(query: &str, replacement: &str, pattern: Option<&str>) -> Config {
+        Config {
+            patterns: vec![query.to_string()],
+            case_sensitive: true,
+            pattern: pattern.map(|p| Regex::new(p).unwrap()),
+            replace: Some(replacement.to_string()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn replace_literal_and_captures() {
+        assert_eq!("a-b-c", replace_spans("a+b+c", &[(1..2, 0), (3..4, 0)], "-", None));
+        let pattern = Regex::new(r"(\w+)@(\w+)").unwrap();
+        assert_eq!("x b at a y", replace_spans("x a@b y", &[(2..5, 0)], "$2 at $1", Some(&pattern)));
+    }
+
+    #[test]
+    fn dry_run_prints_diff_without_touching_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, "frog\r\nbog\nfrog!").unwrap();
+        let mut config = config("frog", "toad", None);
+        config.dry_run = true;
+
+        let mut out = Vec::new();
+        let changed = rewrite(&mut out, &fs::read(&path).unwrap()[..], &path, &config).unwrap();
+        assert_eq!(2, changed);
+        let p = path.display();
+        assert_eq!(
+            format!("--- {}\n+++ {}\n@@ -1 +1 @@\n-frog\n+toad\n@@ -3 +3 @@\n-frog!\n+toad!\n", p, p),
+            String::from_utf8(out).unwrap()
+        );
+        assert_eq!("frog\r\nbog\nfrog!", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn in_place_keeps_line_endings_and_invalid_utf8() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, b"frog\r\nfr\xffog frog\nfrog").unwrap();
+        let config = config("frog", "toad", Some("f(r)og"));
+
+        let changed = rewrite(&mut io::sink(), &fs::read(&path).unwrap()[..], &path, &config).unwrap();
+        assert_eq!(2, changed);
+        assert_eq!(&b"toad\r\nfr\xffog frog\ntoad"[..], &fs::read(&path).unwrap()[..]);
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+}
@@ -0,0 +1,238 @@
+use std::borrow::Cow;
+use std::io::{self, BufRead};
+use std::ops::Range;
+
+use regex::Regex;
+
+use crate::{CaseInsensitive, Match};
+
+/// 1行の中からマッチ範囲を探す方法
+/// 独自の照合方法で`Searcher`を使いたいときはこれを実装する
+pub trait Matcher {
+    /// `line`の`start`バイト目以降で最初にマッチする範囲を返す
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>>;
+}
+
+/// 固定文字列として大文字小文字を区別して探す
+impl Matcher for str {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        line[start..].find(self).map(|i| start + i..start + i + self.len())
+    }
+}
+
+impl Matcher for String {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        self.as_str().find_at(line, start)
+    }
+}
+
+impl Matcher for Regex {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        Regex::find_at(self, line, start).map(|m| m.range())
+    }
+}
+
+impl Matcher for CaseInsensitive {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        CaseInsensitive::find_at(self, line, start)
+    }
+}
+
+impl<M: Matcher + ?Sized> Matcher for &M {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        (**self).find_at(line, start)
+    }
+}
+
+impl<M: Matcher + ?Sized> Matcher for Box<M> {
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        (**self).find_at(line, start)
+    }
+}
+
+/// `Matcher`を使って任意の`BufRead`を1行ずつ検索する
+#[derive(Debug, Clone)]
+pub struct Searcher<M> {
+    matcher: M,
+    invert_match: bool,
+}
+
+impl<M: Matcher> Searcher<M> {
+    pub fn new(matcher: M) -> Searcher<M> {
+        Searcher { matcher, invert_match: false }
+    }
+
+    /// 真ならマッチしなかった行を範囲が空の`Match`として返す
+    pub fn invert_match(mut self, yes: bool) -> Searcher<M> {
+        self.invert_match = yes;
+        self
+    }
+
+    /// `line`が選択されるなら、その行で最初にマッチした範囲を返す(`invert_match`なら空の範囲)
+    pub(crate) fn select(&self, line: &str) -> Option<Range<usize>> {
+        let span = self.matcher.find_at(line, 0);
+        if span.is_some() != self.invert_match {
+            Some(span.unwrap_or(0..0))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn matcher(&self) -> &M {
+        &self.matcher
+    }
+
+    /// `reader`から選択された行を順に返すイテレータを作る
+    /// 入力は次の要素が求められたときに必要な分だけ読むので、ソケットや展開中のストリームにも使える
+    pub fn search_reader<R: BufRead>(&self, reader: R) -> Matches<'_, M, R> {
+        Matches { searcher: self, lines: LineReader::new(reader), done: false }
+    }
+}
+
+/// `Searcher::search_reader`が返すイテレータ
+/// 行の区切りは`search`と同じく`\n`で、行末の`\r`は取り除く
+/// UTF-8として不正なバイト列は置換文字に置き換えて扱う。読み込みに失敗したらエラーを1度だけ返して終わる
+#[derive(Debug)]
+pub struct Matches<'s, M, R> {
+    searcher: &'s Searcher<M>,
+    lines: LineReader<R>,
+    done: bool,
+}
+
+impl<M: Matcher, R: BufRead> Iterator for Matches<'_, M, R> {
+    type Item = io::Result<Match<'static>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let line = match self.lines.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            let text = line.text();
+            if let Some(span) = self.searcher.select(&text) {
+                return Some(Ok(Match {
+                    line_number: line.number,
+                    byte_offset: line.byte_offset,
+                    line: Cow::Owned(text.into_owned()),
+                    span,
+                }));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// 入力を1行ずつ使い回しのバッファに読み込む
+/// 巨大な入力でもメモリ使用量は最も長い行の分だけで済む
+#[derive(Debug)]
+pub(crate) struct LineReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    line_number: usize,
+    byte_offset: usize,
+}
+
+/// `LineReader`が読んだ1行
+pub(crate) struct Line<'b> {
+    /// 1始まりの行番号
+    pub(crate) number: usize,
+    /// 入力の先頭から行頭までのバイトオフセット
+    pub(crate) byte_offset: usize,
+    /// 行末の`\n`と、その直前の`\r`を除いた行の中身
+    pub(crate) body: &'b [u8],
+    /// 取り除いた行末。最終行に改行がなければ空
+    pub(crate) terminator: &'b [u8],
+}
+
+impl Line<'_> {
+    /// 行の中身を文字列として返す。UTF-8として不正なバイト列は置換文字に置き換える
+    pub(crate) fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.body)
+    }
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub(crate) fn new(reader: R) -> LineReader<R> {
+        LineReader { reader, buf: Vec::new(), line_number: 0, byte_offset: 0 }
+    }
+
+    /// 次の1行を読む。入力の終わりに達したら`None`を返す
+    pub(crate) fn next_line(&mut self) -> io::Result<Option<Line<'_>>> {
+        self.buf.clear();
+        let len = self.reader.read_until(b'\n', &mut self.buf)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        let byte_offset = self.byte_offset;
+        self.byte_offset += len;
+        let body = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        let body = body.strip_suffix(b"\r").unwrap_or(body);
+        let (body, terminator) = self.buf.split_at(body.len());
+        Ok(Some(Line { number: self.line_number, byte_offset, body, terminator }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    /// 読み込むと必ず失敗する入力。接続が切れたソケットの代わり
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+        }
+    }
+
+    #[test]
+    fn matches_are_lazy() {
+        let searcher = Searcher::new("rust");
+        let input = io::BufReader::new(b"trust me\n".chain(Failing));
+        let mut matches = searcher.search_reader(input);
+        let first = matches.next().unwrap().unwrap();
+        assert_eq!((1, 0, "trust me", 1..5), (first.line_number, first.byte_offset, first.line.as_ref(), first.span));
+        assert_eq!(io::ErrorKind::ConnectionReset, matches.next().unwrap().unwrap_err().kind());
+        assert!(matches.next().is_none());
+    }
+
+    #[test]
+    fn matcher_implementations() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.\nTrust me.";
+        let lines = |matcher: &dyn Matcher, invert: bool| -> Vec<(usize, usize)> {
+            Searcher::new(matcher)
+                .invert_match(invert)
+                .search_reader(contents.as_bytes())
+                .map(|m| m.map(|m| (m.line_number, m.byte_offset)))
+                .collect::<io::Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(vec![(2, 7)], lines(&"duct".to_string(), false));
+        assert_eq!(vec![(1, 0), (4, 43)], lines(&CaseInsensitive::new("rUsT"), false));
+        assert_eq!(vec![(2, 7)], lines(&Regex::new("^[A-Z]").unwrap(), true));
+    }
+
+    #[test]
+    fn line_reader_splits_terminators() {
+        let mut lines = LineReader::new(&b"one\r\ntwo\n\xffthree"[..]);
+        let mut read = Vec::new();
+        while let Some(line) = lines.next_line().unwrap() {
+            read.push((line.number, line.byte_offset, line.body.to_vec(), line.terminator.to_vec(), line.text().into_owned()));
+        }
+        assert_eq!(
+            vec![
+                (1, 0, b"one".to_vec(), b"\r\n".to_vec(), "one".to_string()),
+                (2, 5, b"two".to_vec(), b"\n".to_vec(), "two".to_string()),
+                (3, 9, b"\xffthree".to_vec(), Vec::new(), "\u{fffd}three".to_string()),
+            ],
+            read
+        );
+    }
+}
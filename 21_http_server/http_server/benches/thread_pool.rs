@@ -0,0 +1,106 @@
+//! 以前の`Arc<Mutex<mpsc::Receiver>>`を全ワーカーで共有する設計と、
+//! 現在のワークスティーリングの`ThreadPool`で、小さなジョブを大量に流したときの処理時間を比べる
+//!
+//! `cargo bench --bench thread_pool`で実行する。スレッド数は環境変数`THREADS`で変えられる
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http_server::ThreadPool;
+
+/// 比較用に残した以前の設計。ジョブを取り出すたびに1つのロックを取り合う
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool { workers, sender: Some(sender) }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// 1ジョブあたりの仕事量。0なら純粋にキューの出し入れの速さを測る
+fn work(spins: u64) -> u64 {
+    (0..spins).fold(0, |acc, i| acc.wrapping_mul(31).wrapping_add(i))
+}
+
+/// `jobs`個のジョブを投入してからプールを捨て、すべて終わるまでの時間を測る
+fn measure<P, E>(new: impl Fn() -> P, execute: E, jobs: usize, spins: u64) -> Duration
+where
+    E: Fn(&P, Box<dyn FnOnce() + Send>),
+{
+    let done = Arc::new(AtomicUsize::new(0));
+    let pool = new();
+    let start = Instant::now();
+    for _ in 0..jobs {
+        let done = Arc::clone(&done);
+        execute(
+            &pool,
+            Box::new(move || {
+                std::hint::black_box(work(spins));
+                done.fetch_add(1, Ordering::Relaxed);
+            }),
+        );
+    }
+    drop(pool);
+    let elapsed = start.elapsed();
+    assert_eq!(jobs, done.load(Ordering::Relaxed));
+    elapsed
+}
+
+/// 最速の回を採る
+fn best_of(runs: usize, f: impl Fn() -> Duration) -> Duration {
+    (0..runs).map(|_| f()).min().unwrap()
+}
+
+fn main() {
+    const JOBS: usize = 200_000;
+    const RUNS: usize = 5;
+    let threads = match std::env::var("THREADS") {
+        Ok(threads) => threads.parse().expect("THREADS must be a positive integer"),
+        Err(_) => thread::available_parallelism().map_or(4, |n| n.get()),
+    };
+    println!("{} jobs, {} threads, best of {} runs", JOBS, threads, RUNS);
+    println!("{:>8} {:>14} {:>14} {:>8}", "spins", "channel", "work-stealing", "speedup");
+    for &spins in &[0, 100, 1_000] {
+        let channel = best_of(RUNS, || measure(|| ChannelPool::new(threads), |p, job| p.execute(job), JOBS, spins));
+        let stealing = best_of(RUNS, || measure(|| ThreadPool::new(threads), |p, job| p.execute(job), JOBS, spins));
+        println!(
+            "{:>8} {:>12.1}ms {:>12.1}ms {:>7.2}x",
+            spins,
+            channel.as_secs_f64() * 1000.0,
+            stealing.as_secs_f64() * 1000.0,
+            channel.as_secs_f64() / stealing.as_secs_f64()
+        );
+    }
+}
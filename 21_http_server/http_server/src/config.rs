@@ -0,0 +1,396 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: http_server [OPTIONS]
+
+Serve hello.html from the pages directory at /, and the files under the
+document root at /static/.
+
+Options:
+  -c, --config FILE            read settings from a TOML file
+  -a, --address ADDR           IP address to listen on (default: 127.0.0.1)
+  -p, --port PORT              port to listen on (default: 7878)
+  -w, --workers NUM            number of worker threads (default: 4)
+      --queue-capacity NUM     connections that may wait for a worker before
+                               new ones get 503 (default: 64)
+  -d, --document-root DIR      directory served at /static/ (default: static)
+      --pages-dir DIR          directory holding hello.html and 404.html
+                               (default: .)
+      --idle-timeout SECS      close keep-alive connections idle this long (default: 5)
+      --shutdown-timeout SECS  wait this long for requests on shutdown (default: 30)
+      --log-level LEVEL        error, warn, info or debug (default: info)
+  -h, --help                   display this help and exit
+  -V, --version                display version information and exit
+
+Every option can also be set with an environment variable such as
+HTTP_SERVER_PORT or HTTP_SERVER_DOCUMENT_ROOT, and in the TOML file with
+keys such as `port` and `document_root`. HTTP_SERVER_CONFIG names the file
+when --config is not given. Options override environment variables, which
+override the file.";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// 環境変数の名前は設定項目の名前を大文字にしてこれを前に付ける
+const ENV_PREFIX: &str = "HTTP_SERVER_";
+
+/// 設定項目の名前。TOMLではこのまま、コマンドラインでは`_`を`-`にして使う
+const KEYS: &[&str] = &[
+    "address",
+    "port",
+    "workers",
+    "queue_capacity",
+    "document_root",
+    "pages_dir",
+    "idle_timeout",
+    "shutdown_timeout",
+    "log_level",
+];
+
+/// ログに出す内容の細かさ。後ろほど多く出す
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn parse(name: &str) -> Option<LogLevel> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        })
+    }
+}
+
+/// 設定を読めなかった理由
+#[derive(Debug)]
+pub enum ConfigError {
+    /// 知らないオプションや設定項目、値が足りないオプション
+    InvalidArgument(String),
+    /// 設定値が不正。`origin`はどこで指定された値か、`expected`は受け付ける値の説明
+    InvalidValue { origin: String, value: String, expected: &'static str },
+    /// 設定ファイルを読めなかった
+    Io(PathBuf, io::Error),
+    /// 設定ファイルがTOMLとして不正
+    Toml(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::InvalidArgument(message) => write!(f, "{}", message),
+            ConfigError::InvalidValue { origin, value, expected } => {
+                write!(f, "invalid value {:?} for {}: expected {}", value, origin, expected)
+            }
+            ConfigError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Toml(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, err) => Some(err),
+            ConfigError::Toml(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// サーバーの設定。既定値、設定ファイル、環境変数、コマンドラインの順に上書きして作る
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub workers: usize,
+    /// ワーカーを待たせておける接続の数。これを超えたら503を返す
+    pub queue_capacity: usize,
+    /// `/static/`以下で配信するディレクトリ
+    pub document_root: PathBuf,
+    /// `/`で返す`hello.html`と、見つからないときに返す`404.html`を置くディレクトリ
+    pub pages_dir: PathBuf,
+    /// 持続的接続で次のリクエストを待つ時間
+    pub idle_timeout: Duration,
+    /// 終了のシグナルを受けてから処理中のリクエストを待つ時間
+    pub shutdown_timeout: Duration,
+    pub log_level: LogLevel,
+    pub help: bool,
+    pub version: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: IpAddr::from([127, 0, 0, 1]),
+            port: 7878,
+            workers: 4,
+            queue_capacity: 64,
+            document_root: PathBuf::from("static"),
+            pages_dir: PathBuf::from("."),
+            idle_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            log_level: LogLevel::Info,
+            help: false,
+            version: false,
+        }
+    }
+}
+
+impl Config {
+    /// 先頭要素をプログラム名とみなして引数列を解釈し、環境変数`vars`と設定ファイルの値と合わせる
+    /// 起動してから失敗しないよう、文書ルートとページのディレクトリが存在することもここで確かめる
+    pub fn new(
+        args: impl Iterator<Item = String>,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut config_file = None;
+        // コマンドラインの値は環境変数と設定ファイルの後で当てはめる
+        let mut options = Vec::new();
+        let mut args = args.skip(1);
+        while let Some(arg) = args.next() {
+            let (name, value) = if let Some(long) = arg.strip_prefix("--") {
+                // `--name=value`と`--name value`の両方を受け付ける
+                match long.find('=') {
+                    Some(i) => (long[..i].to_string(), Some(long[i + 1..].to_string())),
+                    None => (long.to_string(), None),
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                // 短いオプションの値は`-p8080`のように続けて書くか、次の引数として渡す
+                let mut chars = arg[1..].chars();
+                let name = match chars.next() {
+                    Some('c') => "config",
+                    Some('a') => "address",
+                    Some('p') => "port",
+                    Some('w') => "workers",
+                    Some('d') => "document-root",
+                    Some('h') => "help",
+                    Some('V') => "version",
+                    _ => return Err(ConfigError::InvalidArgument(format!("Unknown option: {}", arg))),
+                };
+                let rest = chars.as_str();
+                (name.to_string(), if rest.is_empty() { None } else { Some(rest.to_string()) })
+            } else {
+                return Err(ConfigError::InvalidArgument(format!("Unexpected argument: {}", arg)));
+            };
+            match name.as_str() {
+                "help" => config.help = true,
+                "version" => config.version = true,
+                _ => {
+                    let key = name.replace('-', "_");
+                    if key != "config" && !KEYS.contains(&key.as_str()) {
+                        return Err(ConfigError::InvalidArgument(format!("Unknown option: --{}", name)));
+                    }
+                    let value = match value.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(ConfigError::InvalidArgument(format!("Missing value for --{}", name))),
+                    };
+                    if key == "config" {
+                        config_file = Some(PathBuf::from(value));
+                    } else {
+                        options.push((key, value, format!("--{}", name)));
+                    }
+                }
+            }
+        }
+        if config.help || config.version {
+            return Ok(config);
+        }
+
+        let mut variables = Vec::new();
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) => key.to_ascii_lowercase(),
+                None => continue,
+            };
+            if key == "config" {
+                config_file = config_file.or_else(|| Some(PathBuf::from(value)));
+            } else if KEYS.contains(&key.as_str()) {
+                variables.push((key, value, name));
+            }
+        }
+
+        if let Some(path) = config_file {
+            for (key, value, origin) in read_file(&path)? {
+                config.set(&key, &value, origin)?;
+            }
+        }
+        for (key, value, origin) in variables.into_iter().chain(options) {
+            config.set(&key, &value, origin)?;
+        }
+
+        if !config.document_root.is_dir() {
+            return Err(ConfigError::InvalidArgument(format!(
+                "Document root is not a directory: {}",
+                config.document_root.display()
+            )));
+        }
+        if !config.pages_dir.is_dir() {
+            return Err(ConfigError::InvalidArgument(format!(
+                "Pages directory is not a directory: {}",
+                config.pages_dir.display()
+            )));
+        }
+        Ok(config)
+    }
+
+    /// 待ち受けるアドレスとポートを`bind`に渡せる形で返す
+    pub fn bind_address(&self) -> (IpAddr, u16) {
+        (self.address, self.port)
+    }
+
+    /// 設定項目`key`に`value`を当てはめる。`origin`はエラーメッセージに使う、値の出どころ
+    fn set(&mut self, key: &str, value: &str, origin: String) -> Result<(), ConfigError> {
+        let invalid = |expected| ConfigError::InvalidValue { origin: origin.clone(), value: value.to_string(), expected };
+        match key {
+            "address" => self.address = value.parse().map_err(|_| invalid("an IPv4 or IPv6 address"))?,
+            "port" => {
+                self.port = match value.parse() {
+                    Ok(port) if port > 0 => port,
+                    _ => return Err(invalid("a port number between 1 and 65535")),
+                }
+            }
+            "workers" => self.workers = parse_positive(value).ok_or_else(|| invalid("a positive integer"))?,
+            "queue_capacity" => {
+                self.queue_capacity = parse_positive(value).ok_or_else(|| invalid("a positive integer"))?
+            }
+            "document_root" if value.is_empty() => return Err(invalid("a directory")),
+            "document_root" => self.document_root = PathBuf::from(value),
+            "pages_dir" if value.is_empty() => return Err(invalid("a directory")),
+            "pages_dir" => self.pages_dir = PathBuf::from(value),
+            "idle_timeout" => {
+                let secs = parse_positive(value).ok_or_else(|| invalid("a positive number of seconds"))?;
+                self.idle_timeout = Duration::from_secs(secs as u64);
+            }
+            "shutdown_timeout" => {
+                let secs: u64 = value.parse().map_err(|_| invalid("a non-negative number of seconds"))?;
+                self.shutdown_timeout = Duration::from_secs(secs);
+            }
+            "log_level" => self.log_level = LogLevel::parse(value).ok_or_else(|| invalid("error, warn, info or debug"))?,
+            _ => unreachable!("unknown setting: {}", key),
+        }
+        Ok(())
+    }
+}
+
+fn parse_positive(value: &str) -> Option<usize> {
+    value.parse().ok().filter(|&n| n > 0)
+}
+
+/// 設定ファイルを読み、(設定項目, 値, 出どころ)の列にする
+/// 数値は文字列にしてからコマンドラインの値と同じように検証する
+fn read_file(path: &Path) -> Result<Vec<(String, String, String)>, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let table: toml::Table = toml::from_str(&contents).map_err(|e| ConfigError::Toml(path.to_path_buf(), e))?;
+    let mut settings = Vec::new();
+    for (key, value) in table {
+        let origin = format!("`{}` in {}", key, path.display());
+        if !KEYS.contains(&key.as_str()) {
+            return Err(ConfigError::InvalidArgument(format!("Unknown setting {}", origin)));
+        }
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            other => {
+                return Err(ConfigError::InvalidValue {
+                    origin,
+                    value: other.to_string(),
+                    expected: "a string or an integer",
+                })
+            }
+        };
+        settings.push((key, value, origin));
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let args = std::iter::once("http_server").chain(args.iter().copied()).map(str::to_string);
+        Config::new(args, vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())))
+    }
+
+    #[test]
+    fn defaults_and_options() {
+        assert_eq!(Config::default(), load(&[], &[]).unwrap());
+
+        let config = load(&["-a", "0.0.0.0", "-p8080", "--workers=8", "--idle-timeout", "2", "--log-level", "DEBUG"], &[])
+            .unwrap();
+        assert_eq!(("0.0.0.0".parse().unwrap(), 8080), config.bind_address());
+        assert_eq!((8, Duration::from_secs(2), LogLevel::Debug), (config.workers, config.idle_timeout, config.log_level));
+
+        assert!(load(&["-h", "--port", "nope"], &[]).unwrap().help);
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(file, "port = 9000\nworkers = 2\nshutdown_timeout = 0\ndocument_root = {:?}", dir.path()).unwrap();
+        let path = path.to_str().unwrap();
+
+        let vars = [("HTTP_SERVER_CONFIG", path), ("HTTP_SERVER_WORKERS", "3"), ("HOME", "/root")];
+        let config = load(&["--workers", "5"], &vars).unwrap();
+        assert_eq!((9000, 5), (config.port, config.workers));
+        assert_eq!((dir.path(), Duration::from_secs(0)), (config.document_root.as_path(), config.shutdown_timeout));
+        assert_eq!(3, load(&[], &vars).unwrap().workers);
+        assert_eq!(2, load(&["-c", path], &[]).unwrap().workers);
+    }
+
+    #[test]
+    fn errors() {
+        let message = |args: &[&str], vars: &[(&str, &str)]| load(args, vars).unwrap_err().to_string();
+        assert_eq!(
+            "invalid value \"0\" for --port: expected a port number between 1 and 65535",
+            message(&["--port", "0"], &[])
+        );
+        assert_eq!(
+            "invalid value \"many\" for HTTP_SERVER_WORKERS: expected a positive integer",
+            message(&[], &[("HTTP_SERVER_WORKERS", "many")])
+        );
+        assert_eq!("Unknown option: --threads", message(&["--threads", "4"], &[]));
+        assert_eq!("Missing value for --address", message(&["-a"], &[]));
+        assert_eq!("Document root is not a directory: no-such-dir", message(&["-d", "no-such-dir"], &[]));
+        assert_eq!(
+            "Pages directory is not a directory: no-such-dir",
+            message(&[], &[("HTTP_SERVER_PAGES_DIR", "no-such-dir")])
+        );
+        assert!(matches!(load(&["-c", "no-such-file.toml"], &[]), Err(ConfigError::Io(..))));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(&path, "prot = 80\n").unwrap();
+        assert!(message(&["-c", path.to_str().unwrap()], &[]).starts_with("Unknown setting `prot`"));
+        fs::write(&path, "workers = 1.5\n").unwrap();
+        assert!(message(&["-c", path.to_str().unwrap()], &[]).ends_with("expected a string or an integer"));
+        fs::write(&path, "port = \n").unwrap();
+        assert!(matches!(load(&["-c", path.to_str().unwrap()], &[]), Err(ConfigError::Toml(..))));
+    }
+}
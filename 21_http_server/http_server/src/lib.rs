@@ -1,7 +1,28 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::iter;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_deque::{Injector, Steal, Stealer};
+use crossbeam_utils::Backoff;
+
+mod config;
+mod request;
+mod response;
+mod router;
+mod static_files;
+
+pub use config::{Config, ConfigError, LogLevel, USAGE, VERSION};
+pub use request::{percent_decode, Headers, Method, ParseError, Request, Version};
+pub use response::{reason_phrase, Response};
+pub use router::{Handler, Params, Router};
+pub use static_files::{format_http_date, mime_type, parse_http_date, StaticFiles};
 
 trait FnBox {
     fn call_box(self: Box<Self>);
@@ -12,29 +33,320 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
+
+/// 寝ているワーカーが通知を取りこぼしても、この間隔で起きてキューを確かめ直す
+const IDLE_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ワーカーが共有する状態
+/// ジョブはまず全体のキュー(`injector`)に入り、ワーカーはそこからまとめて自分の両端キューに取り込む
+/// 自分のキューが空になったワーカーは全体のキューか、他のワーカーのキューから盗む
+/// 1つのロックを全ワーカーで取り合う代わりに、ほとんどの取り出しはロックなしで済む
+struct Shared {
+    injector: Injector<Job>,
+    /// 各ワーカーの両端キューから盗むためのハンドル。添字はワーカーのid
+    stealers: Vec<Stealer<Job>>,
+    /// ジョブがなくて寝ている(寝ようとしている)ワーカーの数
+    sleepers: AtomicUsize,
+    sleep_lock: Mutex<()>,
+    wakeup: Condvar,
+    /// 真になったら、ワーカーはキューを空にしてから終わる
+    shutting_down: AtomicBool,
+    /// 動いているワーカーの数。ワーカーは終了するときに減らして`finished`で通知する
+    running: Mutex<usize>,
+    finished: Condvar,
+    /// パニックしたジョブの数
+    panicked_jobs: AtomicUsize,
+    /// キューに入れておけるジョブの数の上限。`None`なら上限なし
+    capacity: Option<usize>,
+    /// 投入されてまだ実行が始まっていないジョブの数。ワーカーの両端キューに取り込まれたものも含む
+    queued: AtomicUsize,
+    /// キューが空くのを待っている`execute`の呼び出しの数
+    blocked_producers: AtomicUsize,
+    space_lock: Mutex<()>,
+    space: Condvar,
+}
+
+impl Shared {
+    /// 自分のキュー、全体のキュー、他のワーカーのキューの順にジョブを探す
+    fn find_job(&self, local: &crossbeam_deque::Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    /// 上限を超えなければキューの枠を1つ確保する
+    fn try_reserve(&self) -> bool {
+        let capacity = self.capacity.unwrap_or(usize::MAX);
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| if queued < capacity { Some(queued + 1) } else { None })
+            .is_ok()
+    }
+
+    /// キューの枠が空くまで待ってから確保する
+    fn reserve(&self) {
+        while !self.try_reserve() {
+            let guard = lock(&self.space_lock);
+            self.blocked_producers.fetch_add(1, Ordering::SeqCst);
+            // `job_started`側のフェンスと対になる。`wait_for_job`と同じ理由
+            atomic::fence(Ordering::SeqCst);
+            if self.capacity.is_some_and(|capacity| self.queued.load(Ordering::SeqCst) >= capacity) {
+                let _ = self.space.wait_timeout(guard, IDLE_RECHECK_INTERVAL);
+            }
+            self.blocked_producers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// ワーカーがジョブを取り出して実行を始めるときに枠を返し、待っている投入側がいれば起こす
+    fn job_started(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if self.blocked_producers.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.space_lock);
+            self.space.notify_one();
+        }
+    }
+
+    /// ジョブが来るまで寝る。終了処理中でジョブも残っていなければ偽を返す
+    fn wait_for_job(&self) -> bool {
+        let guard = lock(&self.sleep_lock);
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        // `notify`側のフェンスと対になり、寝る前の確認とジョブの追加が互いに見えることを保証する
+        atomic::fence(Ordering::SeqCst);
+        let mut keep_running = true;
+        if !self.has_jobs() {
+            if self.shutting_down.load(Ordering::SeqCst) {
+                keep_running = false;
+            } else {
+                let _ = self.wakeup.wait_timeout(guard, IDLE_RECHECK_INTERVAL);
+            }
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        keep_running
+    }
+
+    /// 寝ているワーカーがいるときだけロックを取って起こす
+    fn notify(&self, all: bool) {
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _guard = lock(&self.sleep_lock);
+            if all {
+                self.wakeup.notify_all();
+            } else {
+                self.wakeup.notify_one();
+            }
+        }
+    }
+}
+
+/// ワーカーのスレッドのハンドル。スレッドが死んで作り直されると中身が新しいスレッドに入れ替わる
+type Slot = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
+/// ジョブはパニックしてもロックを持っていないので、毒されたMutexもそのまま使ってよい
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// パニックのペイロードから表示用のメッセージを取り出す
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
 
 struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
+    thread: Slot,
 }
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                let job = receiver.lock().unwrap().recv().unwrap();
-                println!("Worker {} got a job; executing.", id);
-                job.call_box();
+    fn new(id: usize, shared: Arc<Shared>, local: crossbeam_deque::Worker<Job>) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
+        Worker::spawn(id, shared, Arc::clone(&thread), local);
+        Worker { thread }
+    }
+
+    fn spawn(id: usize, shared: Arc<Shared>, slot: Slot, local: crossbeam_deque::Worker<Job>) {
+        // 新しいスレッドが自分のハンドルより先に`slot`を書き換えないよう、ロックしたまま起動する
+        let mut handle = lock(&slot);
+        let sentinel = Sentinel { id, shared, slot: Arc::clone(&slot), local: Some(local) };
+        *handle = Some(thread::spawn(move || sentinel.run()));
+    }
+}
+
+/// ワーカースレッドが持つ値。ジョブの外でパニックしてスレッドが巻き戻されるときは、
+/// Dropで代わりのスレッドを起動してプールの大きさを保つ
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+    slot: Slot,
+    /// このワーカーの両端キュー。作り直したスレッドに残りのジョブごと引き継ぐ
+    local: Option<crossbeam_deque::Worker<Job>>,
+}
+impl Sentinel {
+    fn run(self) {
+        let local = self.local.as_ref().expect("a running worker owns its queue");
+        // ジョブが途切れてもすぐには寝ず、少し待ってから探し直す。寝起きのシステムコールを減らすため
+        let backoff = Backoff::new();
+        loop {
+            let job = match self.shared.find_job(local) {
+                Some(job) => job,
+                None if !backoff.is_completed() => {
+                    backoff.snooze();
+                    continue;
+                }
+                None if self.shared.wait_for_job() => continue,
+                // 終了処理中で、キューに残ったジョブもなくなった
+                None => break,
+            };
+            backoff.reset();
+            self.shared.job_started();
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| job.call_box())) {
+                self.shared.panicked_jobs.fetch_add(1, Ordering::SeqCst);
+                eprintln!("Worker {} job panicked: {}", self.id, panic_message(&*payload));
             }
-        });
+        }
+    }
+}
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!("Worker {} died; respawning.", self.id);
+            let local = self.local.take().expect("a running worker owns its queue");
+            Worker::spawn(self.id, Arc::clone(&self.shared), Arc::clone(&self.slot), local);
+        } else {
+            *lock(&self.shared.running) -= 1;
+            self.shared.finished.notify_all();
+        }
+    }
+}
+
+/// `JobHandle`から結果を受け取れなかった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// ジョブがパニックした。値はパニックのメッセージ
+    Panicked(String),
+    /// ジョブが実行されないまま捨てられた
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "job panicked: {}", message),
+            JoinError::Cancelled => write!(f, "job was cancelled before it ran"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// `ThreadPool::submit`で投入したジョブの結果を受け取るハンドル
+/// 結果を受け取る前にハンドルを捨ててもジョブはそのまま実行される
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    /// 結果を受け取ったら`None`にする
+    receiver: Option<mpsc::Receiver<thread::Result<T>>>,
+}
+
+impl<T> JobHandle<T> {
+    /// ジョブが終わるまで待って結果を返す
+    pub fn join(self) -> Result<T, JoinError> {
+        JobHandle::convert(self.receiver().recv().map_err(|_| ()))
+    }
+
+    /// ジョブが終わっていれば結果を返し、まだなら待たずに`None`を返す
+    /// ## Panics
+    /// すでに結果を受け取ったハンドルで呼ぶとパニックする
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        match self.receiver().try_recv() {
+            Err(mpsc::TryRecvError::Empty) => None,
+            result => {
+                self.receiver = None;
+                Some(JobHandle::convert(result.map_err(|_| ())))
+            }
+        }
+    }
+
+    /// ジョブが終わるのを最大`timeout`だけ待つ。時間内に終わらなければ`None`を返し、後でまた待てる
+    /// ## Panics
+    /// すでに結果を受け取ったハンドルで呼ぶとパニックする
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JoinError>> {
+        match self.receiver().recv_timeout(timeout) {
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            result => {
+                self.receiver = None;
+                Some(JobHandle::convert(result.map_err(|_| ())))
+            }
+        }
+    }
+
+    fn receiver(&self) -> &mpsc::Receiver<thread::Result<T>> {
+        self.receiver.as_ref().expect("the result of this job has already been taken")
+    }
+
+    /// 送信側が結果を送らずに消えたのは、ジョブが実行されずに捨てられたとき
+    fn convert(result: Result<thread::Result<T>, ()>) -> Result<T, JoinError> {
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(payload)) => Err(JoinError::Panicked(panic_message(&*payload).to_string())),
+            Err(()) => Err(JoinError::Cancelled),
+        }
+    }
+}
+
+/// `ThreadPool::try_execute`でキューが一杯だったときのエラー。受け付けなかったジョブを持つ
+pub struct QueueFull<T>(pub T);
+
+impl<T> QueueFull<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for QueueFull<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("QueueFull(..)")
+    }
+}
 
-        Worker { id, thread }
+impl<T> fmt::Display for QueueFull<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread pool queue is full")
+    }
+}
+
+impl<T> std::error::Error for QueueFull<T> {}
+
+/// プールの外から実行待ちのジョブの数を調べるためのハンドル
+/// ジョブの中にも渡せるので、長く居座るジョブが後ろで待っているジョブに場所を譲るかどうかの判断に使える
+#[derive(Clone)]
+pub struct QueueMonitor {
+    shared: Arc<Shared>,
+}
+
+impl QueueMonitor {
+    /// 投入されてまだ実行が始まっていないジョブの数
+    pub fn queued_jobs(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
     }
 }
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    shared: Arc<Shared>,
 }
 impl ThreadPool {
     /// Create a new ThreadPool.
@@ -42,28 +354,325 @@ impl ThreadPool {
     /// ## Panics
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_capacity(size, None)
+    }
+
+    /// 実行待ちのジョブを最大`queue_capacity`個までしか溜めないプールを作る
+    /// キューが一杯のとき、`execute`と`submit`は空くまで待ち、`try_execute`はすぐにエラーを返す
+    /// ## Panics
+    /// `size`か`queue_capacity`が0のときはパニックする
+    pub fn bounded(size: usize, queue_capacity: usize) -> ThreadPool {
+        assert!(queue_capacity > 0);
+        ThreadPool::with_capacity(size, Some(queue_capacity))
+    }
+
+    fn with_capacity(size: usize, capacity: Option<usize>) -> ThreadPool {
         assert!(size > 0);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let locals: Vec<_> = (0..size).map(|_| crossbeam_deque::Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(crossbeam_deque::Worker::stealer).collect(),
+            sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            shutting_down: AtomicBool::new(false),
+            running: Mutex::new(size),
+            finished: Condvar::new(),
+            panicked_jobs: AtomicUsize::new(0),
+            capacity,
+            queued: AtomicUsize::new(0),
+            blocked_producers: AtomicUsize::new(0),
+            space_lock: Mutex::new(()),
+            space: Condvar::new(),
+        });
         let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for (id, local) in locals.into_iter().enumerate() {
+            workers.push(Worker::new(id, Arc::clone(&shared), local));
         }
-        ThreadPool { workers, sender }
+        ThreadPool { workers, shared }
     }
+    /// ジョブがパニックしてもワーカーは巻き込まれず、次のジョブを続けて処理する
+    /// 上限付きのプールでキューが一杯なら、空くまで待つ
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        self.shared.reserve();
+        self.push(Box::new(f));
+    }
+
+    /// キューが一杯なら待たずに、ジョブを`QueueFull`に入れて返す
+    /// 上限のないプールでは常に成功する
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.shared.try_reserve() {
+            return Err(QueueFull(f));
+        }
+        self.push(Box::new(f));
+        Ok(())
+    }
+
+    /// 枠を確保済みのジョブをキューに入れる
+    fn push(&self, job: Job) {
+        self.shared.injector.push(job);
+        self.shared.notify(false);
+    }
+
+    /// 値を返すジョブを投入し、結果を受け取るハンドルを返す
+    /// ジョブのパニックはハンドルに`JoinError::Panicked`として伝わる
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let shared = Arc::clone(&self.shared);
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            if result.is_err() {
+                shared.panicked_jobs.fetch_add(1, Ordering::SeqCst);
+            }
+            // ハンドルが捨てられていれば結果も捨てる
+            let _ = sender.send(result);
+        });
+        JobHandle { receiver: Some(receiver) }
+    }
+
+    /// 投入されてまだ実行が始まっていないジョブの数
+    pub fn queued_jobs(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// 実行待ちのジョブの数を調べるハンドルを返す
+    pub fn queue_monitor(&self) -> QueueMonitor {
+        QueueMonitor { shared: Arc::clone(&self.shared) }
+    }
+
+    /// これまでにパニックしたジョブの数
+    pub fn panicked_jobs(&self) -> usize {
+        self.shared.panicked_jobs.load(Ordering::SeqCst)
+    }
+
+    /// 新しいジョブの受け付けをやめ、実行中とキューに残っているジョブが終わるのを最大`timeout`だけ待つ
+    /// 時間内にすべてのワーカーが終われば真を返す。間に合わなかったワーカーは待たずに切り離す
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        self.begin_shutdown();
+        let deadline = Instant::now() + timeout;
+        let mut remaining = lock(&self.shared.running);
+        while *remaining > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let (guard, _) = self.shared.finished.wait_timeout(remaining, deadline - now).unwrap_or_else(PoisonError::into_inner);
+            remaining = guard;
+        }
+        let all_finished = *remaining == 0;
+        drop(remaining);
+        if all_finished {
+            self.join_workers();
+        } else {
+            // JoinHandleを捨てるとスレッドは切り離され、Dropでも待たない
+            self.workers.clear();
+        }
+        all_finished
+    }
+
+    fn begin_shutdown(&self) {
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        self.shared.notify(true);
+    }
+
+    fn join_workers(&mut self) {
+        for worker in self.workers.drain(..) {
+            // 作り直されたスレッドがあれば、それが終わるまで続けて待つ
+            while let Some(thread) = lock(&worker.thread).take() {
+                let _ = thread.join();
+            }
+        }
     }
 }
 impl Drop for ThreadPool {
+    /// 残っているジョブをすべて実行し終えるまで待つ
     fn drop(&mut self) {
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            worker.thread.join().unwrap();
+        self.begin_shutdown();
+        self.join_workers();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shutdown_drains_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
         }
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(8, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_timeout() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| thread::sleep(Duration::from_secs(2)));
+        let start = Instant::now();
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn drop_waits_for_jobs() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(3);
+            for _ in 0..6 {
+                let done = Arc::clone(&done);
+                pool.execute(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(6, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panicking_jobs_do_not_shrink_the_pool() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for i in 0..10 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                if i % 2 == 0 {
+                    panic!("job {} failed", i);
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(5, done.load(Ordering::SeqCst));
+    }
+
+    /// 捨てるとパニックする値。ジョブの外でワーカーを死なせるのに使う
+    struct Bomb;
+
+    impl Drop for Bomb {
+        fn drop(&mut self) {
+            panic!("payload exploded");
+        }
+    }
+
+    #[test]
+    fn dead_workers_are_respawned() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic::panic_any(Bomb));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap());
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(1, pool.panicked_jobs());
+        assert!(pool.shutdown(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn submit_returns_results() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<JobHandle<u64>> = (1..=10).map(|n| pool.submit(move || (1..=n).product())).collect();
+        let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(3_628_800, results[9]);
+
+        let handle = pool.submit(|| -> u32 { panic!("division by zero") });
+        assert_eq!(Err(JoinError::Panicked("division by zero".to_string())), handle.join());
+        assert_eq!(1, pool.panicked_jobs());
+    }
+
+    #[test]
+    fn try_join_and_timeout() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let mut handle = pool.submit(move || {
+            receiver.recv().unwrap();
+            "done"
+        });
+        assert_eq!(None, handle.try_join());
+        assert_eq!(None, handle.join_timeout(Duration::from_millis(20)));
+        sender.send(()).unwrap();
+        assert_eq!(Some(Ok("done")), handle.join_timeout(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn idle_workers_steal_from_blocked_ones() {
+        // 最初のジョブを取ったワーカーが後続のジョブもまとめて取り込んで止まっても、
+        // 他のワーカーがそれを盗んで進める。盗めなければ最初のジョブが終わらず行き詰まる
+        let pool = ThreadPool::new(2);
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocker = pool.submit(move || blocked.recv().unwrap());
+        let handles: Vec<JobHandle<usize>> = (0..100).map(|i| pool.submit(move || i)).collect();
+        let sum: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(4950, sum);
+        release.send(()).unwrap();
+        blocker.join().unwrap();
+    }
+
+    /// 唯一のワーカーを止めておき、その間に投入したジョブをキューに溜める
+    fn blocked_pool(capacity: usize) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::bounded(1, capacity);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        running.recv_timeout(Duration::from_secs(10)).unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn try_execute_reports_a_full_queue() {
+        let (pool, release) = blocked_pool(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        let job = || {
+            let done = Arc::clone(&done);
+            move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let monitor = pool.queue_monitor();
+        assert!(pool.try_execute(job()).is_ok());
+        assert!(pool.try_execute(job()).is_ok());
+        assert_eq!((2, 2), (pool.queued_jobs(), monitor.queued_jobs()));
+        let rejected = pool.try_execute(job()).unwrap_err();
+        assert_eq!("thread pool queue is full", rejected.to_string());
+
+        release.send(()).unwrap();
+        // 突き返されたジョブは空いてから投入し直せる
+        pool.execute(rejected.into_inner());
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(3, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn execute_waits_for_space() {
+        let (pool, release) = blocked_pool(1);
+        pool.execute(|| {});
+        let pool = Arc::new(pool);
+        let (sender, receiver) = mpsc::channel();
+        let producer = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.execute(move || sender.send(()).unwrap()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+        release.send(()).unwrap();
+        producer.join().unwrap();
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
     }
 }
@@ -2,44 +2,337 @@ extern crate http_server;
 
 use std::net::TcpStream;
 use std::net::TcpListener;
-use std::io::Read;
-use std::io::Write;
-use std::fs::File;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::Shutdown;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use http_server::ThreadPool;
+use std::time::{Duration, Instant};
+use http_server::{Config, LogLevel, QueueMonitor, Request, Response, Router, StaticFiles, ThreadPool, Version};
 
-fn main() {
-    let listener = TcpListener::bind("10.10.10.11:7878").unwrap();
-    let pool = ThreadPool::new(4);
-
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+/// 持続的接続で次のリクエストを待つ間に、終了処理やワーカーを待つ接続がないか確かめる間隔
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// 終了のシグナルを受けて、止まっているacceptを起こすための接続を待つ時間
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+/// 503と一緒にクライアントへ伝える、再試行までの秒数
+const RETRY_AFTER_SECS: u64 = 1;
+/// 1つの接続で処理するリクエストの数の上限
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+/// 設定されたログの細かさ。`LogLevel`を数値にして持つ
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Info as usize);
+
+/// 設定された細かさ以下のメッセージだけを標準エラー出力に書く
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $level as usize <= LOG_LEVEL.load(Ordering::Relaxed) {
+            eprintln!("[{}] {}", $level, format_args!($($arg)*));
+        }
     };
+}
+
+fn main() {
+    let config = Config::new(env::args(), env::vars()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        eprintln!("{}", http_server::USAGE);
+        process::exit(1);
+    });
+    if config.help {
+        println!("{}", http_server::USAGE);
+        return;
+    }
+    if config.version {
+        println!("http_server {}", http_server::VERSION);
+        return;
+    }
+    LOG_LEVEL.store(config.log_level as usize, Ordering::Relaxed);
+
+    let listener = TcpListener::bind(config.bind_address()).unwrap_or_else(|err| {
+        log!(LogLevel::Error, "failed to listen on {}:{}: {}", config.address, config.port, err);
+        process::exit(1);
+    });
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = Arc::clone(&shutdown);
+        let wake = wake_address(listener.local_addr().unwrap());
+        // SIGINTとSIGTERMの両方で呼ばれる
+        // acceptで止まっているメインスレッドは自分で接続して起こし、フラグに気付かせる
+        ctrlc::set_handler(move || {
+            shutdown.store(true, Ordering::SeqCst);
+            if let Err(e) = TcpStream::connect_timeout(&wake, WAKE_TIMEOUT) {
+                log!(LogLevel::Warn, "failed to wake the listener: {}", e);
+            }
+        })
+        .unwrap();
+    }
+    let pool = ThreadPool::bounded(config.workers, config.queue_capacity);
+    let queue = pool.queue_monitor();
+    let files = StaticFiles::new(&config.document_root);
+    let hello = config.pages_dir.join("hello.html");
+    let sleepy_hello = hello.clone();
+    let not_found = config.pages_dir.join("404.html");
+    let router = Arc::new(
+        Router::new()
+            .get("/", move |_, _| html_file(200, &hello))
+            .get("/sleep", move |_, _| {
+                thread::sleep(Duration::from_secs(5));
+                html_file(200, &sleepy_hello)
+            })
+            .get("/static/*path", move |request, params| files.serve(request, params.get("path").unwrap_or_default()))
+            .not_found(move |_, _| html_file(404, &not_found)),
+    );
+    log!(LogLevel::Info, "listening on {}:{} with {} workers", config.address, config.port, config.workers);
+
+    loop {
+        let accepted = listener.accept();
+        // 起こすための接続か、シグナルの後に来た接続なので、応答せずに閉じる
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log!(LogLevel::Warn, "failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        // ジョブに渡したストリームは取り出せないので、断るときのために複製しておく
+        let overflow = match stream.try_clone() {
+            Ok(overflow) => overflow,
+            Err(e) => {
+                log!(LogLevel::Warn, "failed to clone connection: {}", e);
+                continue;
+            }
+        };
+        let router = Arc::clone(&router);
+        let shutdown = Arc::clone(&shutdown);
+        let queue = queue.clone();
+        let idle_timeout = config.idle_timeout;
+        if pool.try_execute(move || handle_connection(stream, &router, &shutdown, &queue, idle_timeout)).is_err() {
+            // 断られたジョブを捨てると元のストリームも閉じるが、複製が残っているので接続は生きている
+            log!(LogLevel::Warn, "all workers are busy; rejecting connection");
+            reject_overloaded(overflow);
+        }
+    }
+
+    log!(LogLevel::Info, "shutting down; waiting for in-flight requests");
+    if !pool.shutdown(config.shutdown_timeout) {
+        log!(LogLevel::Warn, "some requests did not finish within {:?}", config.shutdown_timeout);
+    }
+}
+
+/// 待ち受けているアドレスに自分から接続するときの宛先。全アドレスで待ち受けているならループバックにつなぐ
+fn wake_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(if address.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+    }
+    address
+}
+
+/// 同じ接続でリクエストを順に読んで応答する。パイプライン化されたリクエストもバッファに残るので順に処理される
+/// クライアントが望まないとき、上限の数に達したとき、エラーを返したとき、終了処理中のときは応答後に閉じる
+fn handle_connection(stream: TcpStream, router: &Router, shutdown: &AtomicBool, queue: &QueueMonitor, idle_timeout: Duration) {
+    // 次のリクエストが来ないまま`idle_timeout`が過ぎたら読み込みがエラーになり、接続を閉じる
+    if let Err(e) = stream.set_read_timeout(Some(idle_timeout)) {
+        log!(LogLevel::Warn, "failed to set read timeout: {}", e);
+        return;
+    }
+    let mut reader = BufReader::new(&stream);
+    for served in 1..=MAX_REQUESTS_PER_CONNECTION {
+        // 持続的接続で次のリクエストを待つ間に終了処理が始まるか、ワーカーを待つ接続が現れたら、
+        // `idle_timeout`を待たずに閉じてワーカーを空ける
+        // 最初のリクエストは受け付けた接続への応答なので、終了処理中でも読んで返す
+        if served > 1 {
+            if !wait_for_request(&mut reader, || shutdown.load(Ordering::SeqCst) || queue.queued_jobs() > 0, idle_timeout) {
+                return;
+            }
+            if let Err(e) = stream.set_read_timeout(Some(idle_timeout)) {
+                log!(LogLevel::Warn, "failed to set read timeout: {}", e);
+                return;
+            }
+        }
+        let (response, keep_alive) = match Request::read_from(&mut reader) {
+            Ok(Some(request)) => {
+                let keep_alive =
+                    request.keep_alive() && served < MAX_REQUESTS_PER_CONNECTION && !shutdown.load(Ordering::SeqCst);
+                // ハンドラーがpanicしても接続を黙って切らず、500を返して閉じる
+                let (response, keep_alive) = match panic::catch_unwind(AssertUnwindSafe(|| router.handle(&request))) {
+                    Ok(response) => (response, keep_alive),
+                    Err(_) => {
+                        log!(LogLevel::Error, "handler for {} {} panicked", request.method, request.path);
+                        (internal_server_error(), false)
+                    }
+                };
+                log!(LogLevel::Debug, "{} {} {}", request.method, request.path, response.status);
+                // HTTP/1.0のクライアントには接続を残すことを明示する必要がある
+                if keep_alive && request.version == Version::Http10 {
+                    (response.with_header("Connection", "keep-alive"), true)
+                } else {
+                    (response, keep_alive)
+                }
+            }
+            // リクエストを送らずに閉じられた
+            Ok(None) => return,
+            // 不正なリクエストの後ろはどこから次のリクエストが始まるか分からないので閉じる
+            Err(e) => match e.status() {
+                Some(status) => {
+                    (Response::text(status, &e.to_string()), false)
+                }
+                // 読み込みの失敗で、待ち時間切れもここに来る
+                None => return,
+            },
+        };
+        let response = if keep_alive { response } else { response.with_header("Connection", "close") };
+        if let Err(e) = response.write_to(&stream) {
+            log!(LogLevel::Debug, "failed to write response: {}", e);
+            return;
+        }
+        if !keep_alive {
+            close_gracefully(&stream);
+            return;
+        }
+    }
+}
+
+/// 次のリクエストの最初のバイトが届くまで待ち、届いたら真を返す
+/// `give_up`が真になったことに気付けるよう、読み込みの待ち時間を短く区切って確かめながら待つ
+/// 接続が閉じられたとき、`idle_timeout`が過ぎたとき、`give_up`が真になったときは偽を返す
+/// パイプライン化されたリクエストがバッファに残っていれば、`give_up`にかかわらず真を返す
+fn wait_for_request(reader: &mut BufReader<&TcpStream>, give_up: impl Fn() -> bool, idle_timeout: Duration) -> bool {
+    let deadline = Instant::now() + idle_timeout;
+    loop {
+        if !reader.buffer().is_empty() {
+            return true;
+        }
+        let now = Instant::now();
+        if give_up() || now >= deadline {
+            return false;
+        }
+        if reader.get_ref().set_read_timeout(Some(IDLE_POLL_INTERVAL.min(deadline - now))).is_err() {
+            return false;
+        }
+        match reader.fill_buf() {
+            Ok(buf) => return !buf.is_empty(),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// ワーカーがすべて塞がっているときに、待たせずに503を返して接続を閉じる
+/// 受け付けの流れを止めないよう、リクエストは読まずに返す
+fn reject_overloaded(stream: TcpStream) {
+    let response = Response::text(503, "Service Unavailable")
+        .with_header("Retry-After", &RETRY_AFTER_SECS.to_string())
+        .with_header("Connection", "close");
+    if let Err(e) = response.write_to(&stream) {
+        log!(LogLevel::Debug, "failed to write response: {}", e);
+        return;
+    }
+    close_gracefully(&stream);
+}
+
+/// 書き込み側だけを先に閉じ、届いている未読のデータを読み捨てる
+/// 未読のリクエストが残ったまま閉じるとRSTが飛び、クライアントが最後の応答を読む前に接続が切れることがある
+fn close_gracefully(mut stream: &TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buf = [0; 4096];
+        while let Ok(n) = stream.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// ページのファイルを読んで返す。読めなければ500を返す
+fn html_file(status: u16, path: &Path) -> Response {
+    match fs::read(path) {
+        Ok(contents) => {
+            Response::new(status).with_header("Content-Type", "text/html; charset=utf-8").with_body(contents)
+        }
+        Err(e) => {
+            log!(LogLevel::Error, "failed to read {}: {}", path.display(), e);
+            internal_server_error()
+        }
+    }
+}
+
+fn internal_server_error() -> Response {
+    Response::text(500, "Internal Server Error")
+}
 
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let response = format!("{}{}", status_line, contents);
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-}
\ No newline at end of file
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// 応答を1つ、ボディの`body`まで読む
+    fn read_response(client: &mut TcpStream, body: &str) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.ends_with(body.as_bytes()) {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed early");
+            response.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn idle_connection_yields_to_waiting_connections() {
+        let (mut client, server) = connected();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let pool = ThreadPool::bounded(1, 4);
+        let queue = pool.queue_monitor();
+        let router = Router::new().get("/", |_, _| Response::new(200).with_body("hi"));
+        let idle_timeout = Duration::from_secs(30);
+        pool.execute(move || handle_connection(server, &router, &AtomicBool::new(false), &queue, idle_timeout));
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(!read_response(&mut client, "hi").contains("Connection: close"));
+        // 唯一のワーカーが塞がっている間に次の接続が来たら、待っている接続を閉じてワーカーを空ける
+        let started = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        assert_eq!(0, client.read(&mut [0; 16]).unwrap());
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn wake_address_is_reachable() {
+        let wake = |address: &str| wake_address(address.parse().unwrap()).to_string();
+        assert_eq!("127.0.0.1:7878", wake("0.0.0.0:7878"));
+        assert_eq!("[::1]:7878", wake("[::]:7878"));
+        assert_eq!("192.168.0.2:80", wake("192.168.0.2:80"));
+    }
+
+    #[test]
+    fn pipelined_requests_are_served_before_giving_up() {
+        let (mut client, server) = connected();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(&server);
+        assert!(wait_for_request(&mut reader, || false, Duration::from_secs(10)));
+        // すでにバッファにあるリクエストは、終了処理中でも読む
+        assert!(wait_for_request(&mut reader, || true, Duration::from_secs(10)));
+        reader.consume(reader.buffer().len());
+        assert!(!wait_for_request(&mut reader, || true, Duration::from_secs(10)));
+        assert!(!wait_for_request(&mut reader, || false, Duration::from_millis(100)));
+    }
+}
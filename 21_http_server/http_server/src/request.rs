@@ -0,0 +1,511 @@
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+/// リクエスト行とヘッダの1行の長さの上限
+pub const MAX_LINE: usize = 8 * 1024;
+/// ヘッダの数の上限
+pub const MAX_HEADERS: usize = 100;
+/// ボディの長さの上限
+pub const MAX_BODY: usize = 8 * 1024 * 1024;
+/// リクエスト行の前に読み飛ばす空行の数の上限
+/// RFC 9112が求めるのは少なくとも1行を無視することだけで、際限なく読むと空行を送り続けるクライアントがワーカーを占有できる
+pub const MAX_LEADING_EMPTY_LINES: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    /// 上記以外のメソッド。大文字小文字はそのまま保持する
+    Other(String),
+}
+
+impl Method {
+    fn parse(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            _ => Method::Other(token.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Other(token) => token,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        })
+    }
+}
+
+/// 受け取った順に並んだヘッダ。名前は大文字小文字を区別せずに引く
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// `name`という名前の最初のヘッダの値を返す
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// `name`という名前のヘッダの値をすべて返す
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// 解析済みのHTTPリクエスト
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// リクエストターゲットのパス部分。パーセントエンコーディングはそのまま
+    pub path: String,
+    /// `?`より後ろの部分。`?`がなければ`None`
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    /// `Content-Length`かchunked転送で受け取ったボディ。chunkedの場合は復号済み
+    pub body: Vec<u8>,
+}
+
+/// リクエストを読めなかった理由
+#[derive(Debug)]
+pub enum ParseError {
+    /// 接続の読み込みに失敗したか、リクエストの途中で接続が閉じられた
+    Io(io::Error),
+    /// HTTPとして不正な入力。値は理由
+    Malformed(&'static str),
+    /// リクエスト行かヘッダが長すぎるか多すぎる
+    HeadersTooLarge,
+    /// ボディが`MAX_BODY`を超える
+    BodyTooLarge,
+    /// HTTP/1.0と1.1以外のバージョン
+    UnsupportedVersion,
+}
+
+impl ParseError {
+    /// クライアントに返すべきステータスコード。接続が使えないときは`None`
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::Io(_) => None,
+            ParseError::Malformed(_) => Some(400),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::BodyTooLarge => Some(413),
+            ParseError::UnsupportedVersion => Some(505),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "{}", err),
+            ParseError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::UnsupportedVersion => write!(f, "unsupported HTTP version"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+/// 1行を読み、行末の`\r\n`(または`\n`)を除いて`line`に入れる
+/// 何も読まずに入力が終わったら偽を返す
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> Result<bool, ParseError> {
+    line.clear();
+    let len = reader.take(MAX_LINE as u64 + 2).read_until(b'\n', line)?;
+    if len == 0 {
+        return Ok(false);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if len > MAX_LINE {
+            ParseError::HeadersTooLarge
+        } else {
+            ParseError::Io(io::ErrorKind::UnexpectedEof.into())
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(true)
+}
+
+/// `%XX`を元のバイトに戻す。不正なエスケープや結果がUTF-8でないときは`None`を返す
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok())?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// RFC 9110のtokenに使える文字
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+impl Request {
+    /// `reader`からリクエストを1つ読む。リクエストが複数回の読み込みにまたがっていてもよい
+    /// 最初の1バイトを読む前に接続が閉じられたら`None`を返す
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+        let mut line = Vec::new();
+        // RFC 9112に従い、リクエスト行の前の空行は読み飛ばす
+        for skipped in 0.. {
+            if !read_line(reader, &mut line)? {
+                return Ok(None);
+            }
+            if !line.is_empty() {
+                break;
+            }
+            if skipped == MAX_LEADING_EMPTY_LINES {
+                return Err(ParseError::Malformed("too many empty lines before the request line"));
+            }
+        }
+        let request_line = std::str::from_utf8(&line).map_err(|_| ParseError::Malformed("request line is not UTF-8"))?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(ParseError::Malformed("invalid request line")),
+        };
+        if !is_token(method) {
+            return Err(ParseError::Malformed("invalid method"));
+        }
+        if !(target.starts_with('/') || target == "*") || target.bytes().any(|b| b.is_ascii_control()) {
+            return Err(ParseError::Malformed("invalid request target"));
+        }
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            v if v.starts_with("HTTP/") && v.len() == 8 => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::Malformed("invalid HTTP version")),
+        };
+        let (path, query) = match target.find('?') {
+            Some(i) => (target[..i].to_string(), Some(target[i + 1..].to_string())),
+            None => (target.to_string(), None),
+        };
+        let method = Method::parse(method);
+
+        let mut headers = Vec::new();
+        loop {
+            if !read_line(reader, &mut line)? {
+                return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            headers.push(parse_header(&line)?);
+        }
+        let headers = Headers(headers);
+        if version == Version::Http11 && headers.get_all("host").count() != 1 {
+            return Err(ParseError::Malformed("HTTP/1.1 requires exactly one Host header"));
+        }
+
+        let body = read_body(reader, &headers)?;
+        Ok(Some(Request { method, path, query, version, headers, body }))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// 応答した後も同じ接続で次のリクエストを受けるか
+    /// HTTP/1.1では`Connection: close`がなければ真、HTTP/1.0では`Connection: keep-alive`があるときだけ真
+    pub fn keep_alive(&self) -> bool {
+        let connection = |option: &str| {
+            self.headers
+                .get_all("connection")
+                .flat_map(|value| value.split(','))
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        };
+        match self.version {
+            Version::Http11 => !connection("close"),
+            Version::Http10 => connection("keep-alive"),
+        }
+    }
+}
+
+fn parse_header(line: &[u8]) -> Result<(String, String), ParseError> {
+    // 行頭の空白による折り返し(obs-fold)は受け付けない
+    if line.starts_with(b" ") || line.starts_with(b"\t") {
+        return Err(ParseError::Malformed("obsolete header line folding"));
+    }
+    let line = std::str::from_utf8(line).map_err(|_| ParseError::Malformed("header is not UTF-8"))?;
+    let colon = line.find(':').ok_or(ParseError::Malformed("header without colon"))?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    if !is_token(name) {
+        return Err(ParseError::Malformed("invalid header name"));
+    }
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::Malformed("invalid header value"));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// `Transfer-Encoding`と`Content-Length`に従ってボディを読む
+/// 両方あるリクエストは解釈の食い違いによるリクエストスマグリングを避けるために拒否する
+fn read_body<R: BufRead>(reader: &mut R, headers: &Headers) -> Result<Vec<u8>, ParseError> {
+    let transfer_encoding = headers.get_all("transfer-encoding").collect::<Vec<_>>().join(",");
+    let content_length = headers.get_all("content-length").collect::<Vec<_>>();
+    if !transfer_encoding.is_empty() {
+        if !content_length.is_empty() {
+            return Err(ParseError::Malformed("both Transfer-Encoding and Content-Length"));
+        }
+        let codings: Vec<&str> = transfer_encoding.split(',').map(str::trim).collect();
+        if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::Malformed("unsupported Transfer-Encoding"));
+        }
+        return read_chunked(reader);
+    }
+    let length = match content_length.as_slice() {
+        [] => return Ok(Vec::new()),
+        [first, rest @ ..] => {
+            if rest.iter().any(|v| v != first) || first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::Malformed("invalid Content-Length"));
+            }
+            first.parse::<usize>().map_err(|_| ParseError::BodyTooLarge)?
+        }
+    };
+    if length > MAX_BODY {
+        return Err(ParseError::BodyTooLarge);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+    let eof = || ParseError::Io(io::ErrorKind::UnexpectedEof.into());
+    loop {
+        if !read_line(reader, &mut line)? {
+            return Err(eof());
+        }
+        // `;`以降のchunk拡張は無視する
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size).map_err(|_| ParseError::Malformed("invalid chunk size"))?.trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::Malformed("invalid chunk size"));
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BodyTooLarge)?;
+        if size == 0 {
+            break;
+        }
+        if size > MAX_BODY - body.len() {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader, &mut line)? {
+            return Err(eof());
+        }
+        if !line.is_empty() {
+            return Err(ParseError::Malformed("chunk data longer than its size"));
+        }
+    }
+    // トレーラーは読み捨てる
+    for _ in 0..=MAX_HEADERS {
+        if !read_line(reader, &mut line)? {
+            return Err(eof());
+        }
+        if line.is_empty() {
+            return Ok(body);
+        }
+    }
+    Err(ParseError::HeadersTooLarge)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<Option<Request>, ParseError> {
+        Request::read_from(&mut io::BufReader::new(input))
+    }
+
+    /// 1回の読み込みで1バイトずつしか返さない入力
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&b, rest)), Some(out)) => {
+                    *out = b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn request_line_and_headers() {
+        let request = parse(b"GET /search?q=rust&page=2 HTTP/1.1\r\nHost: localhost\r\nX-Trace:  abc \r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(Method::Get, request.method);
+        assert_eq!("/search", request.path);
+        assert_eq!(Some("q=rust&page=2"), request.query.as_deref());
+        assert_eq!(Version::Http11, request.version);
+        assert_eq!(Some("abc"), request.header("x-trace"));
+        assert_eq!(2, request.headers.len());
+        assert!(request.body.is_empty());
+
+        let request = parse(b"\r\nPURGE / HTTP/1.0\n\n").unwrap().unwrap();
+        assert_eq!((Method::Other("PURGE".to_string()), Version::Http10), (request.method, request.version));
+        assert!(parse(b"").unwrap().is_none());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(Some("a b/ü".to_string()), percent_decode("a%20b%2F%C3%BC"));
+        assert_eq!(None, percent_decode("%2"));
+        assert_eq!(None, percent_decode("%+1"));
+        assert_eq!(None, percent_decode("%FF"));
+    }
+
+    #[test]
+    fn bodies() {
+        let request = parse(b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").unwrap().unwrap();
+        assert_eq!(b"hello", &request.body[..]);
+
+        let input = b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: t\r\n\r\n";
+        let request = parse(input).unwrap().unwrap();
+        assert_eq!(b"hello, world", &request.body[..]);
+
+        // 1バイトずつ届いても同じ結果になる
+        let request = Request::read_from(&mut io::BufReader::new(Trickle(input))).unwrap().unwrap();
+        assert_eq!(b"hello, world", &request.body[..]);
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let input = b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n\
+            POST /b HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc\
+            GET /c HTTP/1.1\r\nHost: x\r\nConnection: Upgrade, close\r\n\r\n";
+        // 最初の読み込みで後続のリクエストもバッファに入るが、次の呼び出しでそこから続けて読める
+        let mut reader = io::BufReader::new(&input[..]);
+        let mut requests = Vec::new();
+        while let Some(request) = Request::read_from(&mut reader).unwrap() {
+            requests.push((request.path.clone(), request.body.clone(), request.keep_alive()));
+        }
+        assert_eq!(
+            vec![
+                ("/a".to_string(), vec![], true),
+                ("/b".to_string(), b"abc".to_vec(), true),
+                ("/c".to_string(), vec![], false),
+            ],
+            requests
+        );
+
+        assert!(!parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap().keep_alive());
+        assert!(parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().unwrap().keep_alive());
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let status = |input: &[u8]| parse(input).unwrap_err().status();
+        assert_eq!(Some(400), status(b"GET /\r\n\r\n"));
+        assert_eq!(Some(400), status(b"\r\n\n\r\nGET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!("/", parse(b"\r\n\nGET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap().unwrap().path);
+        assert_eq!(Some(400), status(b"GET  / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET index.html HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(505), status(b"GET / HTTP/2.0\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost : x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n"));
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: -1\r\n\r\n"));
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"));
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n")
+        );
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"));
+        assert_eq!(Some(413), status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999\r\n\r\n"));
+        let long = format!("GET /{} HTTP/1.1\r\nHost: x\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(Some(431), status(long.as_bytes()));
+        // 途中で切れたリクエストには応答しない
+        assert_eq!(None, status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhel"));
+        assert_eq!(None, status(b"GET / HTTP/1.1\r\nHost: x\r\n"));
+    }
+}
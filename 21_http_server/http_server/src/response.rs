@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// ステータスコードに対応する理由句
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// ボディを持てるステータスか。1xx・204・304には`Content-Length`も付けない
+pub(crate) fn allows_body(status: u16) -> bool {
+    !(100..200).contains(&status) && status != 204 && status != 304
+}
+
+/// クライアントに返すレスポンス
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// ボディをファイルから流すときの、開いたファイルと送るバイト数。`body`の代わりに使う
+    file: Option<(File, u64)>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response { status, headers: Vec::new(), body: Vec::new(), file: None }
+    }
+
+    /// `message`を1行の本文にしたプレーンテキストのレスポンス。エラーなどの短い応答に使う
+    pub fn text(status: u16, message: &str) -> Response {
+        Response::new(status).with_header("Content-Type", "text/plain; charset=utf-8").with_body(format!("{}\n", message))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self.file = None;
+        self
+    }
+
+    /// `file`の現在位置から`len`バイトをボディにする
+    /// 書き出すときに少しずつ読むので、大きなファイルもメモリに読み込まずに送れる
+    pub fn with_file(mut self, file: File, len: u64) -> Response {
+        self.body.clear();
+        self.file = Some((file, len));
+        self
+    }
+
+    /// ボディのバイト数
+    pub(crate) fn body_len(&self) -> u64 {
+        match &self.file {
+            Some((_, len)) => *len,
+            None => self.body.len() as u64,
+        }
+    }
+
+    pub(crate) fn clear_body(&mut self) {
+        self.body.clear();
+        self.file = None;
+    }
+
+    /// `name`という名前の最初のヘッダの値を返す。名前は大文字小文字を区別しない
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// ステータス行・ヘッダ・ボディを書き出す
+    /// ボディを持てるステータスで`Content-Length`が設定されていなければボディの長さから付ける
+    pub fn write_to<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if allows_body(self.status) && self.header("content-length").is_none() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body_len()));
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())?;
+        match &self.file {
+            Some((file, len)) => {
+                // 途中でファイルが縮んだら約束した長さを送れないので、エラーにして接続を閉じてもらう
+                if io::copy(&mut file.take(*len), &mut out)? < *len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being sent"));
+                }
+            }
+            None => out.write_all(&self.body)?,
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Seek;
+
+    #[test]
+    fn write_adds_content_length() {
+        let mut out = Vec::new();
+        Response::new(404).with_header("Content-Type", "text/plain").with_body("nope").write_to(&mut out).unwrap();
+        assert_eq!(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\nnope",
+            String::from_utf8(out).unwrap()
+        );
+
+        let mut out = Vec::new();
+        Response::new(304).with_header("ETag", "\"1\"").write_to(&mut out).unwrap();
+        assert_eq!("HTTP/1.1 304 Not Modified\r\nETag: \"1\"\r\n\r\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn write_streams_file_body() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.seek(io::SeekFrom::Start(2)).unwrap();
+        let mut out = Vec::new();
+        Response::new(206).with_file(file.try_clone().unwrap(), 3).write_to(&mut out).unwrap();
+        assert_eq!("HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\n234", String::from_utf8(out).unwrap());
+
+        // ファイルが約束した長さより短ければエラーにする
+        file.seek(io::SeekFrom::Start(8)).unwrap();
+        let error = Response::new(200).with_file(file, 5).write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+    }
+}
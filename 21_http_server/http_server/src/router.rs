@@ -0,0 +1,244 @@
+use crate::request::{percent_decode, Method, Request};
+use crate::response::{allows_body, Response};
+
+/// パスパターンから取り出したパラメータ。値はパーセントデコード済み
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// リクエストとパラメータを受け取ってレスポンスを返す関数
+/// 複数のワーカースレッドから同時に呼ばれるので`Send + Sync`が必要
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync + 'static>;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    /// そのまま一致する必要がある部分
+    Literal(String),
+    /// `:name`。任意の1セグメントに一致する
+    Param(String),
+    /// `*name`。残りのパス全体(空でもよい)に一致する。パターンの最後にだけ置ける
+    Wildcard(String),
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(pattern.starts_with('/'), "route pattern must start with '/': {}", pattern);
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(i + 1 == parts.len(), "wildcard must be the last segment: {}", pattern);
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+/// パスがパターンに一致すればパラメータを返す
+fn match_path(segments: &[Segment], path: &[String]) -> Option<Params> {
+    let mut params = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                params.push((name.clone(), path.get(i..).unwrap_or_default().join("/")));
+                return Some(Params(params));
+            }
+            Segment::Literal(literal) if path.get(i) != Some(literal) => return None,
+            Segment::Literal(_) => {}
+            Segment::Param(name) => params.push((name.clone(), path.get(i)?.clone())),
+        }
+    }
+    if path.len() == segments.len() {
+        Some(Params(params))
+    } else {
+        None
+    }
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+/// メソッドとパスパターンの組にハンドラを登録し、リクエストを振り分ける
+/// 複数のルートに一致するときは先に登録したものを使う
+/// パスに一致するルートがなければ404を、パスには一致するがメソッドが違えば`Allow`付きの405を返す
+/// HEADはGETのルートで処理し、ボディだけを取り除く
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_, _| Response::text(404, "Not Found")),
+        }
+    }
+
+    /// `pattern`は`/users/:id`や`/static/*path`のように書く
+    /// ## Panics
+    /// パターンが`/`で始まらないときや、ワイルドカードが最後のセグメントでないときはパニックする
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route { method, segments: parse_pattern(pattern), handler: Box::new(handler) });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// どのルートにも一致しなかったときのハンドラを差し替える
+    pub fn not_found<F>(mut self, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let path: Option<Vec<String>> = match request.path.strip_prefix('/') {
+            Some(path) => path.split('/').map(percent_decode).collect(),
+            None => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => return Response::text(400, "Bad Request"),
+        };
+        let mut allowed: Vec<&Method> = Vec::new();
+        for route in &self.routes {
+            let params = match match_path(&route.segments, &path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if route.method == request.method {
+                return (route.handler)(request, &params);
+            }
+            if request.method == Method::Head && route.method == Method::Get {
+                let mut response = (route.handler)(request, &params);
+                strip_body(&mut response);
+                return response;
+            }
+            allowed.push(&route.method);
+        }
+        if allowed.is_empty() {
+            let mut response = (self.not_found)(request, &Params::default());
+            if request.method == Method::Head {
+                strip_body(&mut response);
+            }
+            return response;
+        }
+        if allowed.contains(&&Method::Get) {
+            allowed.push(&Method::Head);
+        }
+        let mut names: Vec<&str> = Vec::new();
+        for method in allowed {
+            if !names.contains(&method.as_str()) {
+                names.push(method.as_str());
+            }
+        }
+        Response::text(405, "Method Not Allowed").with_header("Allow", &names.join(", "))
+    }
+}
+
+/// HEADへの応答ではボディを送らず、GETのときの長さだけを伝える
+fn strip_body(response: &mut Response) {
+    if allows_body(response.status) && response.header("content-length").is_none() {
+        let length = response.body_len().to_string();
+        response.headers.push(("Content-Length".to_string(), length));
+    }
+    response.clear_body();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, target);
+        Request::read_from(&mut io::BufReader::new(raw.as_bytes())).unwrap().unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_, _| Response::new(200).with_body("home"))
+            .get("/users/new", |_, _| Response::new(200).with_body("form"))
+            .get("/users/:id", |_, params| Response::new(200).with_body(format!("user {}", params.get("id").unwrap())))
+            .post("/users/:id", |request, _| Response::new(201).with_body(request.body.clone()))
+            .get("/users/:id/posts/:post", |_, params| {
+                Response::new(200).with_body(format!("{} {}", params.get("id").unwrap(), params.get("post").unwrap()))
+            })
+            .get("/static/*path", |_, params| Response::new(200).with_body(params.get("path").unwrap().to_string()))
+    }
+
+    #[test]
+    fn routes_and_params() {
+        let router = router();
+        assert_eq!("home", body(&router.handle(&request("GET", "/"))));
+        assert_eq!("form", body(&router.handle(&request("GET", "/users/new"))));
+        assert_eq!("user 42", body(&router.handle(&request("GET", "/users/42?verbose=1"))));
+        assert_eq!("user j doe", body(&router.handle(&request("GET", "/users/j%20doe"))));
+        assert_eq!("7 9", body(&router.handle(&request("GET", "/users/7/posts/9"))));
+        assert_eq!("css/site.css", body(&router.handle(&request("GET", "/static/css/site.css"))));
+        assert_eq!("", body(&router.handle(&request("GET", "/static/"))));
+    }
+
+    #[test]
+    fn automatic_responses() {
+        let router = router();
+        assert_eq!(404, router.handle(&request("GET", "/users")).status);
+        assert_eq!(404, router.handle(&request("GET", "/users/1/")).status);
+        assert_eq!(400, router.handle(&request("GET", "/users/%zz")).status);
+
+        let response = router.handle(&request("DELETE", "/users/1"));
+        assert_eq!((405, Some("GET, POST, HEAD")), (response.status, response.header("allow")));
+
+        let response = router.handle(&request("HEAD", "/users/1"));
+        assert_eq!((200, Some("6")), (response.status, response.header("content-length")));
+        assert!(response.body.is_empty());
+
+        let router = router.not_found(|request, _| Response::new(404).with_body(format!("no {}", request.path)));
+        assert_eq!("no /nothing", body(&router.handle(&request("GET", "/nothing"))));
+    }
+}
@@ -0,0 +1,451 @@
+use std::convert::TryFrom;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::Request;
+use crate::response::Response;
+
+/// ディレクトリへのリクエストにはこのファイルを返す。なければファイル一覧を生成する
+const INDEX_FILE: &str = "index.html";
+
+/// 拡張子から`Content-Type`を決める。知らない拡張子はバイナリとして扱う
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" | "rs" | "toml" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// ディレクトリ以下のファイルを配信するハンドラ
+/// ルートの外を指すパス(`..`やルートの外へのシンボリックリンク)には403を返す
+/// 一覧に載せない隠しファイルと隠しディレクトリは、存在しないものとして404を返す
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+        StaticFiles { root: root.into() }
+    }
+
+    /// ルートからの相対パス`path`(パーセントデコード済み)のファイルを返す
+    /// ルーターのワイルドカードで受けたパスをそのまま渡す想定
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let mut file_path = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Response::text(403, "Forbidden"),
+                s if s.starts_with('.') => return Response::text(404, "Not Found"),
+                s if s.contains('\\') || s.contains('\0') => return Response::text(403, "Forbidden"),
+                s => file_path.push(s),
+            }
+        }
+        let root = match self.root.canonicalize() {
+            Ok(root) => root,
+            Err(_) => return Response::text(404, "Not Found"),
+        };
+        // シンボリックリンクを解決した結果がルートの中にあるか確かめる
+        let file_path = match file_path.canonicalize() {
+            Ok(p) if p.starts_with(&root) => p,
+            Ok(_) => return Response::text(403, "Forbidden"),
+            Err(_) => return Response::text(404, "Not Found"),
+        };
+        let metadata = match fs::metadata(&file_path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Response::text(404, "Not Found"),
+        };
+        if !metadata.is_dir() {
+            return serve_file(request, &file_path, &metadata);
+        }
+        // 相対リンクが正しく解決されるように、ディレクトリは`/`で終わるURLに揃える
+        if !request.path.ends_with('/') {
+            let mut location = format!("{}/", request.path);
+            if let Some(query) = &request.query {
+                location = format!("{}?{}", location, query);
+            }
+            return Response::text(301, "Moved Permanently").with_header("Location", &location);
+        }
+        let index = file_path.join(INDEX_FILE);
+        match fs::metadata(&index) {
+            Ok(metadata) if metadata.is_file() => serve_file(request, &index, &metadata),
+            _ => match listing(&file_path, &request.path) {
+                Ok(html) => Response::new(200).with_header("Content-Type", "text/html; charset=utf-8").with_body(html),
+                Err(_) => Response::text(403, "Forbidden"),
+            },
+        }
+    }
+}
+
+fn serve_file(request: &Request, path: &Path, metadata: &Metadata) -> Response {
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = entity_tag(len, modified);
+    let last_modified = modified.map(format_http_date);
+    let mut response = Response::new(200)
+        .with_header("Content-Type", mime_type(path))
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", &etag);
+    if let Some(last_modified) = &last_modified {
+        response = response.with_header("Last-Modified", last_modified);
+    }
+    if is_not_modified(request, &etag, modified) {
+        response.status = 304;
+        return response;
+    }
+
+    let range = match request.header("range") {
+        // `If-Range`の検証子が現在のものと違えば、範囲指定を無視して全体を返す
+        Some(_) if !if_range_matches(request, &etag, last_modified.as_deref()) => None,
+        Some(value) => parse_range(value, len),
+        None => None,
+    };
+    let (start, end) = match range {
+        None => (0, len),
+        Some(Ok((first, last))) => {
+            response.status = 206;
+            response = response.with_header("Content-Range", &format!("bytes {}-{}/{}", first, last, len));
+            (first, last + 1)
+        }
+        Some(Err(())) => {
+            response.status = 416;
+            return response.with_header("Content-Range", &format!("bytes */{}", len));
+        }
+    };
+    match open_at(path, start) {
+        Ok(file) => response.with_file(file, end - start),
+        Err(_) => Response::text(500, "Internal Server Error"),
+    }
+}
+
+/// ファイルを開き、`start`バイト目から読める状態にする
+fn open_at(path: &Path, start: u64) -> io::Result<File> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(file)
+}
+
+/// 大きさと更新日時から作る強いETag
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    format!("\"{:x}-{:x}.{:x}\"", len, mtime.as_secs(), mtime.subsec_nanos())
+}
+
+/// `If-None-Match`があればそれだけで、なければ`If-Modified-Since`で判断する
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(value) = request.header("if-none-match") {
+        // 弱い比較なので`W/`の有無は無視する
+        let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return value.split(',').any(|tag| tag.trim() == "*" || strip(tag) == strip(etag));
+    }
+    let since = request.header("if-modified-since").and_then(parse_http_date);
+    let modified = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// `If-Range`がないか、現在のETagか`Last-Modified`と完全に一致するか
+fn if_range_matches(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match request.header("if-range") {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => Some(value) == last_modified,
+    }
+}
+
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// `Range: bytes=...`を解釈し、返すべき範囲(両端を含む)を返す
+/// 解釈できない指定や複数の範囲の指定は無視して`None`を、満たせない範囲なら`Some(Err(()))`を返す
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let dash = spec.find('-')?;
+    let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+    if first.is_empty() {
+        // `bytes=-N`は末尾のNバイト
+        let suffix = parse_digits(last)?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len - suffix.min(len), len - 1)));
+    }
+    let first = parse_digits(first)?;
+    let last = if last.is_empty() { u64::MAX } else { parse_digits(last)? };
+    if last < first {
+        return None;
+    }
+    if first >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((first, last.min(len - 1))))
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// 1970-01-01からの日数をグレゴリオ暦の年月日に変換する
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// `civil_from_days`の逆変換
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT`の形式(IMF-fixdate)で書く
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// IMF-fixdate形式の日時をUNIX時間の秒に変換する。廃止された形式には対応しない
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let day = parse_digits(day).filter(|d| (1..=31).contains(d))? as u32;
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let year = parse_digits(year)? as i64;
+    let hms: Vec<u64> = time.split(':').map(parse_digits).collect::<Option<_>>()?;
+    let (hour, minute, second) = match hms.as_slice() {
+        [h, m, s] if *h < 24 && *m < 60 && *s < 61 => (*h, *m, *s),
+        _ => return None,
+    };
+    let days = days_from_civil(year, month, day);
+    u64::try_from(days).ok().map(|days| days * 86_400 + hour * 3600 + minute * 60 + second)
+}
+
+/// リンクに使えるようにURLの1セグメントをパーセントエンコードする
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// ディレクトリのファイル一覧のページを作る。隠しファイルは載せない
+fn listing(dir: &Path, url_path: &str) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let suffix = if entry.file_type()?.is_dir() { "/" } else { "" };
+        entries.push((name, suffix));
+    }
+    entries.sort();
+    let title = escape_html(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<ul>\n",
+        title, title
+    );
+    if url_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, suffix) in entries {
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode(&name),
+            suffix,
+            escape_html(&name),
+            suffix
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", target);
+        for (name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        Request::read_from(&mut io::BufReader::new(raw.as_bytes())).unwrap().unwrap()
+    }
+
+    /// ファイルから流すボディも含めて、クライアントに届くボディを取り出す
+    fn body(response: &Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let start = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        out.split_off(start)
+    }
+
+    fn site() -> (tempfile::TempDir, StaticFiles) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("public/docs")).unwrap();
+        fs::create_dir(dir.path().join("public/blog")).unwrap();
+        fs::write(dir.path().join("public/hello.txt"), "0123456789").unwrap();
+        fs::write(dir.path().join("public/blog/index.html"), "<h1>blog</h1>").unwrap();
+        fs::write(dir.path().join("public/docs/a <b>.css"), "").unwrap();
+        fs::write(dir.path().join("public/.env"), "SECRET=1").unwrap();
+        fs::create_dir(dir.path().join("public/.git")).unwrap();
+        fs::write(dir.path().join("public/.git/config"), "").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let files = StaticFiles::new(dir.path().join("public"));
+        (dir, files)
+    }
+
+    #[test]
+    fn http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", format_http_date(time));
+        assert_eq!(Some(784_111_777), parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!("Thu, 29 Feb 2024 00:00:00 GMT", format_http_date(UNIX_EPOCH + Duration::from_secs(1_709_164_800)));
+        assert_eq!(Some(1_709_164_800), parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"));
+        assert_eq!(None, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(Some(Ok((0, 4))), parse_range("bytes=0-4", 10));
+        assert_eq!(Some(Ok((5, 9))), parse_range("bytes=5-", 10));
+        assert_eq!(Some(Ok((7, 9))), parse_range("bytes=-3", 10));
+        assert_eq!(Some(Ok((0, 9))), parse_range("bytes=-30", 10));
+        assert_eq!(Some(Ok((8, 9))), parse_range("bytes=8-20", 10));
+        assert_eq!(Some(Err(())), parse_range("bytes=10-", 10));
+        assert_eq!(None, parse_range("bytes=0-1,3-4", 10));
+        assert_eq!(None, parse_range("bytes=4-2", 10));
+        assert_eq!(None, parse_range("items=0-1", 10));
+    }
+
+    #[test]
+    fn serve_files() {
+        let (_dir, files) = site();
+        let response = files.serve(&request("/static/hello.txt", &[]), "hello.txt");
+        assert_eq!((200, Some("text/plain; charset=utf-8")), (response.status, response.header("content-type")));
+        assert_eq!(b"0123456789", &body(&response)[..]);
+        assert!(response.header("last-modified").is_some());
+
+        let response = files.serve(&request("/static/hello.txt", &[("Range", "bytes=2-4")]), "hello.txt");
+        assert_eq!((206, Some("bytes 2-4/10")), (response.status, response.header("content-range")));
+        assert_eq!(b"234", &body(&response)[..]);
+        let response = files.serve(&request("/static/hello.txt", &[("Range", "bytes=20-")]), "hello.txt");
+        assert_eq!((416, Some("bytes */10")), (response.status, response.header("content-range")));
+        let response = files.serve(&request("/", &[("Range", "bytes=0-1"), ("If-Range", "\"old\"")]), "hello.txt");
+        assert_eq!(200, response.status);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let (_dir, files) = site();
+        let first = files.serve(&request("/hello.txt", &[]), "hello.txt");
+        let etag = first.header("etag").unwrap();
+        let response = files.serve(&request("/hello.txt", &[("If-None-Match", &format!("\"x\", W/{}", etag))]), "hello.txt");
+        assert_eq!(304, response.status);
+        assert!(body(&response).is_empty());
+        assert_eq!(200, files.serve(&request("/hello.txt", &[("If-None-Match", "\"x\"")]), "hello.txt").status);
+
+        let last_modified = first.header("last-modified").unwrap();
+        let response = files.serve(&request("/hello.txt", &[("If-Modified-Since", last_modified)]), "hello.txt");
+        assert_eq!(304, response.status);
+        let old = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(200, files.serve(&request("/hello.txt", &[("If-Modified-Since", old)]), "hello.txt").status);
+    }
+
+    #[test]
+    fn directories_and_traversal() {
+        let (dir, files) = site();
+        let response = files.serve(&request("/static/blog", &[]), "blog");
+        assert_eq!((301, Some("/static/blog/")), (response.status, response.header("location")));
+        assert_eq!(b"<h1>blog</h1>", &body(&files.serve(&request("/static/blog/", &[]), "blog/"))[..]);
+
+        let response = files.serve(&request("/static/docs/", &[]), "docs/");
+        let html = String::from_utf8(response.body).unwrap();
+        assert!(html.contains("<a href=\"a%20%3Cb%3E.css\">a &lt;b&gt;.css</a>"), "{}", html);
+        let html = String::from_utf8(files.serve(&request("/static/", &[]), "").body).unwrap();
+        assert!(!html.contains(".env") && !html.contains(".git"), "{}", html);
+        // 一覧に載せない隠しファイルは直接指定しても返さない
+        assert_eq!(404, files.serve(&request("/static/.env", &[]), ".env").status);
+        assert_eq!(404, files.serve(&request("/static/.git/config", &[]), ".git/config").status);
+        assert_eq!(404, files.serve(&request("/static/.git/", &[]), ".git/").status);
+        assert!(String::from_utf8(files.serve(&request("/static/", &[]), "").body).unwrap().contains("blog/"));
+
+        assert_eq!(403, files.serve(&request("/static/../secret.txt", &[]), "../secret.txt").status);
+        assert_eq!(404, files.serve(&request("/static/missing", &[]), "missing").status);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret.txt"), dir.path().join("public/link")).unwrap();
+            assert_eq!(403, files.serve(&request("/static/link", &[]), "link").status);
+        }
+    }
+}